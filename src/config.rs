@@ -0,0 +1,57 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use anyhow::{Context, Result};
+use datamine_exporter::spreadsheet::{FormatFlag, Header, Sentinels};
+use serde::Deserialize;
+
+/// Settings that don't fit on the command line, loaded from a json file.
+///
+/// Example:
+/// ```json
+/// {
+///     "headers": {
+///         "Recipes": { "row": 1, "rows": 2 }
+///     },
+///     "renames": {
+///         "buy_(bells)": "buy_price"
+///     },
+///     "format_flags": {
+///         "#ff0000": "unobtainable",
+///         "strikethrough": "cut"
+///     },
+///     "sentinels": {
+///         "global": { "NA": null },
+///         "columns": { "buy": { "NFS": null } }
+///     }
+/// }
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Header location by sheet title
+    pub headers: HashMap<String, Header>,
+    /// Explicit column names, keyed by the normalized column name
+    pub renames: HashMap<String, String>,
+    /// Flag names keyed by format condition (`#rrggbb`, `bold` or `strikethrough`)
+    pub format_flags: BTreeMap<String, String>,
+    /// Replacements for placeholder strings, in addition to those of `--sentinels`
+    pub sentinels: Sentinels,
+}
+
+impl Config {
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let config = serde_json::from_slice(&json)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    pub(crate) fn format_flags(&self) -> Result<Vec<FormatFlag>> {
+        self.format_flags.iter()
+            .map(|(condition, flag)| Ok(FormatFlag::new(condition.parse()?, flag)))
+            .collect()
+    }
+}
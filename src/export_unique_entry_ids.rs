@@ -1,7 +1,7 @@
 use std::fs::{self, File};
 use std::io::Write;
 
-use anyhow::*;
+use anyhow::{Context, Result};
 use crate::{Datamine, EXPORT_DIR, Opt};
 
 pub(crate) fn export_unique_entry_ids(opt: &Opt, datamine: &Datamine) -> Result<()> {
@@ -16,7 +16,7 @@ pub(crate) fn export_unique_entry_ids(opt: &Opt, datamine: &Datamine) -> Result<
         writeln!(file, "{}", sheet_title)?;
 
         for row in &sheet.rows {
-            let id = match row.get("unique_entry_id") {
+            let id = match sheet.field(row, "unique_entry_id") {
                 Some(id) => id,
                 None => continue,
             };
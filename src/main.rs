@@ -1,7 +1,7 @@
 use std::collections::*;
 use std::path::*;
-use std::{ops, sync::*};
-use anyhow::*;
+use std::{ops, sync::*, time};
+use anyhow::{bail, Context, Result};
 use datamine_exporter::*;
 use futures::prelude::*;
 use indicatif::{ProgressBar, MultiProgress, HumanBytes};
@@ -12,9 +12,15 @@ use tokio::fs;
 mod export_unique_entry_ids;
 use export_unique_entry_ids::export_unique_entry_ids;
 
+mod config;
+use config::Config;
+use spreadsheet::client::{retry::check_status, RetryPolicy};
+
 const CACHE_DIR: &str = "cache";
 const EXPORT_DIR: &str = "export";
 const IMAGE_EXPORT_PATH: &str = "export/images";
+const ERROR_REPORT_PATH: &str = "export/errors";
+const METADATA_EXPORT_PATH: &str = "export/meta";
 
 #[derive(StructOpt)]
 #[structopt(
@@ -30,6 +36,186 @@ struct Opt {
     id_prefix: Option<String>,
     #[structopt(long = "id-suffix", requires = "only-ids", help = "The suffix for id's when using `--only-ids`")]
     id_suffix: Option<String>,
+    #[structopt(long = "typed-values", help = "Export numbers and booleans as json numbers and booleans instead of strings")]
+    typed_values: bool,
+    #[structopt(long = "lenient", help = "Skip cells that can't be decoded and print a warning instead of failing")]
+    lenient: bool,
+    #[structopt(
+        long = "error-values",
+        default_value = "null",
+        help = "How to export cells containing errors like #N/A: `null` or `tagged`",
+    )]
+    error_values: spreadsheet::ErrorValueMode,
+    #[structopt(
+        long = "links",
+        default_value = "ignore",
+        help = "How to export hyperlinks: `ignore`, `object` ({ text, url }) or `sibling` (<column>_url)",
+    )]
+    links: spreadsheet::LinkMode,
+    #[structopt(long = "notes", help = "Export cell notes of each row as a `_notes` map keyed by column")]
+    notes: bool,
+    #[structopt(long = "image-metadata", help = "Export the mode and size of IMAGE formulas as a `<column>_image_meta` field")]
+    image_metadata: bool,
+    #[structopt(long = "fill-merged", help = "Copy the value of merged cells into every cell covered by the merge")]
+    fill_merged: bool,
+    #[structopt(long = "header-row", default_value = "0", help = "Zero based index of the first header row")]
+    header_row: usize,
+    #[structopt(long = "header-rows", default_value = "1", help = "Number of header rows, joined into compound column names")]
+    header_rows: usize,
+    #[structopt(long = "detect-header", help = "Use the frozen rows of each sheet as header")]
+    detect_header: bool,
+    #[structopt(
+        long = "column-names",
+        default_value = "suffix",
+        help = "How to handle blank and duplicate column titles: `suffix` (_col_<n>, _2, ...) or `strict` (fail)",
+    )]
+    column_names: spreadsheet::ColumnNames,
+    #[structopt(
+        long = "column-naming",
+        default_value = "legacy",
+        help = "How to turn column titles into keys: `legacy` (buy_(bells)), `snake` (buy_bells), `camel` (buyBells) or `verbatim` (Buy (Bells))",
+    )]
+    column_naming: spreadsheet::ColumnNaming,
+    #[structopt(long = "column-order", help = "Keep the keys of rows in spreadsheet column order instead of sorting them")]
+    column_order: bool,
+    #[structopt(long = "validate-enums", help = "Check that values of dropdown columns are allowed by the dropdown")]
+    validate_enums: bool,
+    #[structopt(long = "source", help = "Add a `_source` field with the spreadsheet row to each exported row")]
+    source: bool,
+    #[structopt(long = "sentinels", help = "Export `NA`, `None` and `-` as null and `Yes`/`No` as booleans")]
+    sentinels: bool,
+    #[structopt(long = "sheet", number_of_values = 1, help = "Only export sheets whose title matches this pattern (`*` and `?` are wildcards)")]
+    sheets: Vec<String>,
+    #[structopt(long = "gid", number_of_values = 1, help = "Only export the sheet with this gid")]
+    gids: Vec<i64>,
+    #[structopt(long = "sheet-index", number_of_values = 1, help = "Only export the sheet at this zero based tab position")]
+    sheet_indices: Vec<usize>,
+    #[structopt(long = "exclude", number_of_values = 1, help = "Don't export sheets whose title matches this pattern")]
+    excluded_sheets: Vec<String>,
+    #[structopt(long = "include-read-me", help = "Don't exclude the `Read Me` sheet, which is excluded by default")]
+    include_read_me: bool,
+    #[structopt(long = "refresh", help = "Download the spreadsheet even if the cached copy is up to date")]
+    refresh: bool,
+    #[structopt(
+        long = "max-age",
+        conflicts_with = "refresh",
+        help = "Use a cached spreadsheet younger than this many seconds without checking whether it changed",
+    )]
+    max_age: Option<u64>,
+    #[structopt(
+        long = "stale-if-error",
+        conflicts_with = "refresh",
+        help = "Use the cached spreadsheet if it can't be checked for changes, instead of failing",
+    )]
+    stale_if_error: bool,
+    #[structopt(long = "retries", default_value = "5", help = "How often to retry failed requests, with exponential backoff")]
+    retries: u32,
+    #[structopt(long = "per-sheet", help = "Download and cache every sheet separately, several at a time")]
+    per_sheet: bool,
+    #[structopt(long = "concurrency", default_value = "4", help = "How many sheets to download at a time with `--per-sheet`")]
+    concurrency: usize,
+    #[structopt(
+        long = "service-account",
+        parse(from_os_str),
+        help = "Json key of a service account to read private spreadsheets with, instead of the API_KEY",
+    )]
+    service_account: Option<PathBuf>,
+    #[structopt(long = "config", parse(from_os_str), help = "Json file with per sheet settings")]
+    config: Option<PathBuf>,
+}
+
+impl Opt {
+    fn sheet_selector(&self) -> spreadsheet::SheetSelector {
+        let mut selector = spreadsheet::SheetSelector::new();
+
+        for pattern in &self.sheets {
+            selector = selector.title(pattern);
+        }
+
+        for &gid in &self.gids {
+            selector = selector.gid(gid);
+        }
+
+        for &index in &self.sheet_indices {
+            selector = selector.index(index);
+        }
+
+        if !self.include_read_me {
+            selector = selector.exclude("Read Me");
+        }
+
+        for pattern in &self.excluded_sheets {
+            selector = selector.exclude(pattern);
+        }
+
+        selector
+    }
+
+    /// Returns the ranges of the selected sheets,
+    /// or no ranges if they can't be known before downloading the spreadsheet.
+    fn ranges(&self) -> Vec<String> {
+        let only_exact_titles = self.gids.is_empty()
+            && self.sheet_indices.is_empty()
+            && self.sheets.iter().all(|title| !title.contains(&['*', '?'][..]));
+
+        if !only_exact_titles {
+            return Vec::new();
+        }
+
+        self.sheets.iter()
+            .map(|title| spreadsheet::sheet_range(title))
+            .collect()
+    }
+
+    fn cache_policy(&self) -> spreadsheet::CachePolicy {
+        match self.max_age {
+            _ if self.refresh => spreadsheet::CachePolicy::Refresh,
+            Some(max_age) => spreadsheet::CachePolicy::MaxAge(time::Duration::from_secs(max_age)),
+            None => spreadsheet::CachePolicy::Revalidate,
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default().with_max_retries(self.retries)
+    }
+
+    fn json_options(&self, config: &Config) -> Result<spreadsheet::JsonOptions> {
+        let mut sentinels = if self.sentinels {
+            spreadsheet::Sentinels::datamine()
+        } else {
+            spreadsheet::Sentinels::default()
+        };
+        sentinels.extend(config.sentinels.clone());
+
+        Ok(spreadsheet::JsonOptions {
+            typed_values: self.typed_values,
+            strictness: if self.lenient {
+                spreadsheet::Strictness::Lenient
+            } else {
+                spreadsheet::Strictness::Strict
+            },
+            error_values: self.error_values,
+            links: self.links,
+            notes: self.notes,
+            image_metadata: self.image_metadata,
+            fill_merged: self.fill_merged,
+            header: spreadsheet::Header {
+                row: self.header_row,
+                rows: self.header_rows,
+                detect: self.detect_header,
+            },
+            sheet_headers: config.headers.clone(),
+            column_names: self.column_names,
+            column_naming: self.column_naming,
+            column_renames: config.renames.clone(),
+            keep_column_order: self.column_order,
+            format_flags: config.format_flags().context("Invalid format flags")?,
+            validate_enums: self.validate_enums,
+            source: self.source,
+            spreadsheet_id: Some(DATAMINE_SHEET_ID.into()),
+            sentinels,
+        })
+    }
 }
 
 #[tokio::main]
@@ -39,29 +225,71 @@ async fn main() -> Result<()> {
     let vars = dotenv::vars()
         .collect::<BTreeMap<String, String>>();
 
-    let api_key = vars.get("API_KEY")
-        .context("API_KEY missing in .env")?;
+    let api_key = vars.get("API_KEY");
+
+    let auth = match (&opt.service_account, api_key) {
+        (Some(path), _) => spreadsheet::ServiceAccount::from_file(path)
+            .context("Failed to load service account key")?
+            .into(),
+        (None, Some(api_key)) => api_key.as_str().into(),
+        (None, None) => bail!("API_KEY missing in .env"),
+    };
+
+    if let Err(err) = run(auth, &opt).await {
+        let mut err = format!("{:?}", err);
+
+        if let Some(api_key) = api_key {
+            err = err.replace(api_key.as_str(), "<REDACTED>");
+        }
 
-    if let Err(err) = run(api_key, &opt).await {
-        let err = format!("{:?}", err).replace(api_key, "<REDACTED>");
         eprintln!("{}", err);
     }
 
     Ok(())
 }
 
-async fn run(api_key: &str, opt: &Opt) -> Result<()> {
-    let client = spreadsheet::Client::new(api_key, CACHE_DIR);
+async fn run(auth: spreadsheet::Auth, opt: &Opt) -> Result<()> {
+    let config = match &opt.config {
+        Some(path) => Config::load(path).context("Failed to load config")?,
+        None => Config::default(),
+    };
 
-    eprintln!(">> Getting datamine");
-    let datamine = client.get(DATAMINE_SHEET_ID, &new_spreadsheet_download_progress("datamine"))
-        .await
-        .context("Failed to get datamine")?;
+    let json_options = opt.json_options(&config)?;
 
-    eprintln!(">> Transforming datamine");
-    let mut datamine = JsonSheet::all_from_spreadsheet(datamine)
-        .map(Datamine)
-        .context("Failed to convert datamine to json sheets")?;
+    let client = spreadsheet::Client::builder(auth)
+        .cache_dir(CACHE_DIR)
+        .cache_policy(opt.cache_policy())
+        .retry_policy(opt.retry_policy())
+        .stale_if_error(opt.stale_if_error)
+        .fields(json_options.fields_mask())
+        .ranges(opt.ranges())
+        .build();
+
+    let mut sheets = BTreeMap::new();
+
+    eprintln!(">> Getting and transforming datamine");
+    let selector = opt.sheet_selector();
+    let on_sheet = |sheet: spreadsheet::Sheet| {
+        let sheet = JsonSheet::from_sheet(&sheet, &json_options)
+            .with_context(|| format!("Failed to convert sheet '{}' to json", sheet.title()))?;
+
+        sheets.insert(sheet.title.clone(), sheet);
+
+        Ok(())
+    };
+
+    if opt.per_sheet {
+        get_sheets_concurrently(&client, &selector, opt.concurrency, on_sheet).await
+            .context("Failed to get datamine")?;
+    } else {
+        let progress = new_spreadsheet_download_progress("datamine");
+
+        client.for_each_sheet(DATAMINE_SHEET_ID, &progress, &selector, on_sheet)
+            .await
+            .context("Failed to get datamine")?;
+    }
+
+    let mut datamine = Datamine(sheets);
 
     if opt.only_ids {
         eprintln!(">> Exporting unique entry IDs");
@@ -75,14 +303,14 @@ async fn run(api_key: &str, opt: &Opt) -> Result<()> {
     //     .await
     //     .context("Failed to get translations")?;
 
-    datamine.assign_filenames_to_recipes()
+    datamine.assign_filenames_to_recipes(json_options.column_naming)
         .context("Failed to assign filenames to recipes")?;
 
     fs::create_dir_all(EXPORT_DIR)
         .await
         .context("Failed to create export directory")?;
 
-    let ref multi_progress = Arc::new(MultiProgress::new());
+    let multi_progress = &Arc::new(MultiProgress::new());
     let total_progress = multi_progress.add(ProgressBar::new(datamine.len() as u64));
     total_progress.enable_steady_tick(500);
     total_progress.set_style(PROGRESSBAR_STYLE.clone());
@@ -92,7 +320,7 @@ async fn run(api_key: &str, opt: &Opt) -> Result<()> {
         move || multi_progress.join().unwrap()
     });
 
-    datamine.export(&total_progress, &multi_progress, opt.download_images).await
+    datamine.export(&total_progress, multi_progress, opt.download_images, opt.retry_policy()).await
         .context("Failed to export datamine")?;
 
     total_progress.finish_and_clear();
@@ -114,12 +342,58 @@ fn normalize_filename_fragment(name: &str) -> String {
     .collect()
 }
 
-async fn download_image_for_row<'a>(
+/// Downloads the selected sheets of the datamine separately, each with its own progress bar.
+async fn get_sheets_concurrently(
+    client: &spreadsheet::Client,
+    selector: &spreadsheet::SheetSelector,
+    concurrency: usize,
+    on_sheet: impl FnMut(spreadsheet::Sheet) -> Result<()>,
+) -> Result<()> {
+    let sheets = client.get_sheet_properties(DATAMINE_SHEET_ID, &new_spreadsheet_download_progress("sheet list"))
+        .await
+        .context("Failed to get sheet list")?
+        .into_iter()
+        .filter(|properties| selector.matches_properties(properties))
+        .collect::<Vec<_>>();
+
+    let multi_progress = Arc::new(MultiProgress::new());
+    let progress_bars = sheets.iter()
+        .map(|properties| (properties.sheet_id(), multi_progress.add(ProgressBar::new_spinner())))
+        .collect::<HashMap<_, _>>();
+
+    tokio::task::spawn_blocking({
+        let multi_progress = multi_progress.clone();
+        move || multi_progress.join().unwrap()
+    });
+
+    let result = client.for_each_sheet_concurrently(
+        DATAMINE_SHEET_ID,
+        &sheets,
+        concurrency,
+        |properties| spreadsheet_download_progress(
+            progress_bars[&properties.sheet_id()].clone(),
+            properties.title().to_owned(),
+        ),
+        on_sheet,
+    )
+    .await;
+
+    // Cached sheets are not downloaded, so their progress bars haven't been finished yet
+    for progress in progress_bars.values() {
+        progress.finish_and_clear();
+    }
+
+    result
+}
+
+async fn download_image_for_row(
     dir: &str,
+    sheet: &JsonSheet,
     row: &Map<String, Value>,
     multi_progress: &MultiProgress,
+    retry_policy: RetryPolicy,
 ) -> Result<()> {
-    let image = match Image::from_row(row) {
+    let image = match Image::from_row(sheet, row) {
         Some(image) => image,
         None => return Ok(()),
     };
@@ -134,7 +408,18 @@ async fn download_image_for_row<'a>(
     progress.enable_steady_tick(150);
     progress.set_message(&format!("Downloading {}", download_path));
 
-    image.download_to(download_path).await
+    let instrument = spreadsheet::client::FnInstrument {
+        this: &progress,
+        starting_request: |_| {},
+        received_bytes: |_, _| {},
+        request_finished: |_| {},
+        retrying: |this, retry, delay, reason| {
+            this.set_message(&format!("Retry {} of {} in {}s: {}", retry, download_path, delay.as_secs(), reason));
+        },
+        using_stale_cache: |_, _| {},
+    };
+
+    image.download_to(&download_path, &retry_policy, &instrument).await
         .with_context(|| image.url.to_string())?;
 
     progress.finish_and_clear();
@@ -148,8 +433,8 @@ struct Image<'a> {
 }
 
 impl<'a> Image<'a> {
-    fn from_row(row: &'a Map<String, Value>) -> Option<Self> {
-        let url = row.get("image").or_else(|| row.get("storage_image"))?.as_str()?;
+    fn from_row(sheet: &JsonSheet, row: &'a Map<String, Value>) -> Option<Self> {
+        let url = sheet.field(row, "image").or_else(|| sheet.field(row, "storage_image"))?.as_str()?;
 
         if url == "NA" {
             return None;
@@ -157,13 +442,15 @@ impl<'a> Image<'a> {
 
         Some(Self {
             url,
-            filename: row.get("filename")?.as_str()?,
+            filename: sheet.field(row, "filename")?.as_str()?,
         })
     }
 
     async fn download(&self) -> Result<impl AsRef<[u8]>> {
         let response = reqwest::get(self.url).await
             .context("Failed to request image")?;
+        let response = check_status(response)
+            .context("Failed to request image")?;
 
         let image = response.bytes().await
             .context("Failed to download image completely")?;
@@ -171,8 +458,13 @@ impl<'a> Image<'a> {
         Ok(image)
     }
 
-    async fn download_to(&self, path: impl AsRef<Path>) -> Result<()> {
-        let image = self.download().await
+    async fn download_to(
+        &self,
+        path: impl AsRef<Path>,
+        retry_policy: &RetryPolicy,
+        instrument: &impl spreadsheet::client::Instrument,
+    ) -> Result<()> {
+        let image = retry_policy.run(instrument, || self.download()).await
             .context("Failed to download image")?;
 
         safe_write(&path, &image).await
@@ -202,18 +494,27 @@ async fn safe_write(path: impl AsRef<Path>, data: impl AsRef<[u8]> + Unpin) -> R
 struct Datamine(pub BTreeMap<String, JsonSheet>);
 
 impl Datamine {
-    fn assign_filenames_to_recipes(&mut self) -> Result<()> {
-        let mut recipes = self.remove("Recipes")
-            .context("Failed to find recipes")?;
+    /// Adds the filenames of the crafted items to every recipe.
+    /// The new field is named like a column titled `Filenames` would be.
+    fn assign_filenames_to_recipes(&mut self, naming: spreadsheet::ColumnNaming) -> Result<()> {
+        let mut recipes = match self.remove("Recipes") {
+            Some(recipes) => recipes,
+            // Recipes are not selected
+            None => return Ok(()),
+        };
+
+        let category_key = recipes.column("category").unwrap_or_default().to_owned();
+        let name_key = recipes.column("name").unwrap_or_default().to_owned();
+        let filenames_key = naming.column_name(&["Filenames"]);
 
         // TODO: Make faster by creating a lookup: category => name => [filenames]
         for recipe in &mut recipes.rows {
-            let category = recipe.get("category")
+            let category = recipe.get(&category_key).map(cell_text)
                 .context("Failed to get category field for a recipe")?
                 .as_str()
                 .context("Category is not a string")?;
 
-            let recipe_name = recipe.get("name")
+            let recipe_name = recipe.get(&name_key).map(cell_text)
                 .context("Failed to get name field for a recipe")?
                 .as_str()
                 .context("Recipe name is not a string")?;
@@ -233,13 +534,13 @@ impl Datamine {
             let mut filenames = Vec::new();
 
             for item in &items.rows {
-                let item_name = item.get("name")
+                let item_name = items.field(item, "name")
                     .context("Failed to get name field for an item")?
                     .as_str()
                     .context("Item name is not a string")?;
 
                 if recipe_name == item_name {
-                    let filename = item.get("filename")
+                    let filename = items.field(item, "filename")
                         .context("Failed to get filename field for an item")?
                         .as_str()
                         .context("Item filename is not a string")?;
@@ -251,7 +552,7 @@ impl Datamine {
 
             let filenames = Value::from(filenames);
 
-            recipe.insert("filenames".into(), filenames);
+            recipe.insert(filenames_key.clone(), filenames);
         }
 
         self.insert("Recipes".into(), recipes);
@@ -259,20 +560,27 @@ impl Datamine {
         Ok(())
     }
 
-    async fn export(&self, total_progress: &ProgressBar, multi_progress: &MultiProgress, with_images: bool) -> Result<()> {
+    async fn export(
+        &self,
+        total_progress: &ProgressBar,
+        multi_progress: &MultiProgress,
+        with_images: bool,
+        retry_policy: RetryPolicy,
+    ) -> Result<()> {
         for (title, sheet) in &**self {
-            if title == "Read Me" {
-                total_progress.inc(1);
-                continue;
-            }
-
             total_progress.set_message(&format!("Processing '{}'", title));
 
             sheet.export_to_dir(EXPORT_DIR).await
                 .with_context(|| format!("Failed to export sheet '{}'", title))?;
 
+            sheet.export_metadata_to_dir(METADATA_EXPORT_PATH).await
+                .with_context(|| format!("Failed to export metadata for sheet '{}'", title))?;
+
+            sheet.export_formula_errors_to_dir(ERROR_REPORT_PATH).await
+                .with_context(|| format!("Failed to export error report for sheet '{}'", title))?;
+
             if with_images {
-                sheet.download_images_to_dir(multi_progress).await
+                sheet.download_images_to_dir(multi_progress, retry_policy).await
                     .with_context(|| format!("Failed to download images for sheet '{}'", title))?;
             }
 
@@ -299,26 +607,59 @@ impl ops::DerefMut for Datamine {
 
 type Row = Map<String, Value>;
 
+/// Returns the text of a cell exported as `{ "text": ..., "url": ... }` with `--links=object`,
+/// `null` for errors exported as `{ "error": ..., "message": ... }` with `--error-values=tagged`
+/// and any other value as is.
+fn cell_text(value: &Value) -> &Value {
+    static NULL: Value = Value::Null;
+
+    match value {
+        Value::Object(object) if object.contains_key("url") => object.get("text").unwrap_or(&NULL),
+        Value::Object(object) if object.contains_key("error") => &NULL,
+        _ => value,
+    }
+}
+
 struct JsonSheet {
     pub title: String,
     pub rows: Vec<Row>,
+    pub formula_errors: Vec<spreadsheet::FormulaError>,
+    pub metadata: spreadsheet::SheetMetadata,
+    /// Column keys by legacy column name
+    pub columns: HashMap<String, String>,
+    /// Serialize the keys of rows in spreadsheet column order
+    pub keep_column_order: bool,
 }
 
 impl JsonSheet {
-    fn all_from_spreadsheet(spreadsheet: Spreadsheet) -> Result<BTreeMap<String, Self>> {
-        spreadsheet
-        .sheets()
-        .map(|sheet| sheet.json_rows().map(|rows| {
-            let title = sheet.title().to_owned();
-            let sheet = Self {
-                title: title.clone(),
-                rows,
-            };
+    fn from_sheet(sheet: &spreadsheet::Sheet, options: &spreadsheet::JsonOptions) -> Result<Self> {
+        let json_rows = sheet.json_rows_with(options)
+            .context("Failed to convert datasheet to json rows")?;
 
-            (title, sheet)
-        }))
-        .collect::<Result<BTreeMap<_, _>>>()
-        .context("Failed to convert datasheet to json rows")
+        for warning in &json_rows.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+
+        Ok(Self {
+            title: sheet.title().to_owned(),
+            metadata: sheet.metadata(&json_rows),
+            rows: json_rows.rows,
+            formula_errors: json_rows.formula_errors,
+            columns: sheet.column_keys_by_legacy_name(options)?,
+            keep_column_order: options.keep_column_order,
+        })
+    }
+
+    /// Returns the key of a well known column, given by its legacy name like `unique_entry_id`,
+    /// whatever naming and renames are used.
+    fn column(&self, legacy_name: &str) -> Option<&str> {
+        self.columns.get(legacy_name).map(String::as_str)
+    }
+
+    /// Returns the value of a well known column of `row`, see `column`.
+    /// Linked cells and error values are unwrapped, see `cell_text`.
+    fn field<'r>(&self, row: &'r Row, legacy_name: &str) -> Option<&'r Value> {
+        row.get(self.column(legacy_name)?).map(cell_text)
     }
 
     async fn export_to_dir(&self, dir: impl AsRef<Path>) -> Result<()> {
@@ -329,11 +670,59 @@ impl JsonSheet {
         self.export_to(path).await
     }
 
+    /// Writes a report of all cells containing errors like `#N/A`.
+    /// Sheets without errors get no report.
+    async fn export_formula_errors_to_dir(&self, dir: impl AsRef<Path>) -> Result<()> {
+        if self.formula_errors.is_empty() {
+            return Ok(());
+        }
+
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let filename = normalize_filename_fragment(&self.title);
+        let path = dir.join(format!("{}.json", filename));
+
+        let json = serde_json::to_vec_pretty(&self.formula_errors)
+            .context("Failed to serialize formula errors to json")?;
+
+        safe_write(&path, &json).await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(())
+    }
+
+    async fn export_metadata_to_dir(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let filename = normalize_filename_fragment(&self.title);
+        let path = dir.join(format!("{}.json", filename));
+
+        let json = serde_json::to_vec_pretty(&self.metadata)
+            .context("Failed to serialize metadata to json")?;
+
+        safe_write(&path, &json).await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(())
+    }
+
     async fn export_to(&self, path: impl AsRef<Path>) -> Result<()> {
         let path = path.as_ref();
 
-        let json = serde_json::to_vec_pretty(&self.rows)
-            .context("Failed to serialize to json")?;
+        let json = if self.keep_column_order {
+            let rows = self.rows.iter()
+                .map(|row| spreadsheet::OrderedRow::new(row, &self.metadata.columns))
+                .collect::<Vec<_>>();
+
+            serde_json::to_vec_pretty(&rows)
+        } else {
+            serde_json::to_vec_pretty(&self.rows)
+        };
+        let json = json.context("Failed to serialize to json")?;
 
         safe_write(path, &json).await
             .with_context(|| format!("Failed to write {}", path.display()))?;
@@ -342,26 +731,26 @@ impl JsonSheet {
     }
 
     // TODO: move this function to Datamine struct
-    async fn download_images_to_dir(&self, multi_progress: &MultiProgress) -> Result<()> {
+    async fn download_images_to_dir(&self, multi_progress: &MultiProgress, retry_policy: RetryPolicy) -> Result<()> {
         let required_fields_exist = self.rows.iter()
-        .all(|row| (row.get("image").is_some() || row.get("storage_image").is_some()) && row.get("filename").is_some());
+        .all(|row| (self.field(row, "image").is_some() || self.field(row, "storage_image").is_some()) && self.field(row, "filename").is_some());
 
         if !required_fields_exist {
             return Ok(());
         }
 
-        let ref total_progress = multi_progress.add(ProgressBar::new(self.rows.len() as u64));
+        let total_progress = &multi_progress.add(ProgressBar::new(self.rows.len() as u64));
         total_progress.set_style(PROGRESSBAR_STYLE_ETA.clone());
         total_progress.set_message("Downloading images");
         total_progress.enable_steady_tick(150);
 
         let dir = normalize_filename_fragment(&self.title);
-        let ref dir = format!("{}/{}", IMAGE_EXPORT_PATH, dir);
+        let dir = &format!("{}/{}", IMAGE_EXPORT_PATH, dir);
         fs::create_dir_all(&dir).await?;
 
         stream::iter(&self.rows).map(Ok)
             .try_for_each_concurrent(10, move |row: &Map<String, Value>| async move {
-                let result = download_image_for_row(dir, &row, multi_progress).await;
+                let result = download_image_for_row(dir, self, row, multi_progress, retry_policy).await;
                 total_progress.inc(1);
                 result
             })
@@ -373,22 +762,177 @@ impl JsonSheet {
     }
 }
 
-fn new_spreadsheet_download_progress(sheet_name: &str) -> impl spreadsheet::client::Instrument + '_ {
+fn new_spreadsheet_download_progress(sheet_name: &str) -> impl spreadsheet::client::Instrument {
+    spreadsheet_download_progress(ProgressBar::new_spinner(), sheet_name.to_owned())
+}
+
+fn spreadsheet_download_progress(progress: ProgressBar, sheet_name: String) -> impl spreadsheet::client::Instrument {
     spreadsheet::client::FnInstrument {
-        this: ProgressBar::new_spinner(),
-        starting_request: move |this| {
+        this: (progress, sheet_name),
+        starting_request: |(this, sheet_name)| {
             this.set_style(indicatif::ProgressStyle::default_spinner());
             this.set_message(&format!("Sending API request for {}", sheet_name));
             this.enable_steady_tick(50);
         },
-        received_bytes: move |this, amount| {
+        received_bytes: |(this, sheet_name), amount| {
             this.inc(amount as u64);
 
             let bytes_downloaded = HumanBytes(this.position());
             this.set_message(&format!("Downloaded {} of {}", bytes_downloaded, sheet_name));
         },
-        request_finished: |this| {
+        request_finished: |(this, _)| {
             this.finish_and_clear();
         },
+        retrying: |(this, sheet_name), retry, delay, reason| {
+            this.set_position(0);
+            this.set_message(&format!("Retry {} for {} in {}s: {}", retry, sheet_name, delay.as_secs(), reason));
+        },
+        using_stale_cache: |(this, sheet_name), reason| {
+            let warning = format!("Warning: Using cached {}, failed to check whether it changed: {}", sheet_name, reason);
+
+            // Hidden progress bars, e.g. when not writing to a terminal, don't print anything
+            if this.is_hidden() {
+                eprintln!("{}", warning);
+            } else {
+                this.println(warning);
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn json_sheet(title: &str, rows: &[&[&str]], options: &spreadsheet::JsonOptions) -> JsonSheet {
+        let rows = rows.iter()
+            .map(|row| row.iter().map(|value| text(value)).collect())
+            .collect::<Vec<_>>();
+
+        json_sheet_from_cells(title, rows, options)
+    }
+
+    fn text(value: &str) -> Value {
+        json!({ "effectiveValue": { "stringValue": value } })
+    }
+
+    fn linked(value: &str) -> Value {
+        json!({ "effectiveValue": { "stringValue": value }, "hyperlink": "https://example.com" })
+    }
+
+    fn json_sheet_from_cells(title: &str, rows: Vec<Vec<Value>>, options: &spreadsheet::JsonOptions) -> JsonSheet {
+        let rows = rows.into_iter()
+            .map(|values| json!({ "values": values }))
+            .collect::<Vec<_>>();
+        let sheet = serde_json::from_value::<spreadsheet::Sheet>(json!({
+            "properties": { "title": title },
+            "data": [{ "rowData": rows }],
+        }))
+        .unwrap();
+
+        JsonSheet::from_sheet(&sheet, options).unwrap()
+    }
+
+    /// Checks that the well known columns are found with the given naming.
+    fn finds_well_known_columns(column_naming: spreadsheet::ColumnNaming) {
+        let options = spreadsheet::JsonOptions {
+            column_naming,
+            ..spreadsheet::JsonOptions::default()
+        };
+        let housewares = json_sheet("Housewares", &[
+            &["Name", "Filename", "Image", "Unique Entry ID"],
+            &["Wooden Chair", "chair", "https://example.com/chair.png", "abc"],
+        ], &options);
+        let recipes = json_sheet("Recipes", &[
+            &["Name", "Category"],
+            &["Wooden Chair", "Housewares"],
+        ], &options);
+
+        let image = Image::from_row(&housewares, &housewares.rows[0]).unwrap();
+        assert_eq!((image.url, image.filename), ("https://example.com/chair.png", "chair"));
+
+        let id = housewares.field(&housewares.rows[0], "unique_entry_id").unwrap();
+        assert_eq!(id, "abc");
+
+        let mut datamine = Datamine(vec![
+            (housewares.title.clone(), housewares),
+            (recipes.title.clone(), recipes),
+        ].into_iter().collect());
+        datamine.assign_filenames_to_recipes(column_naming).unwrap();
+
+        let recipe = &datamine["Recipes"].rows[0];
+        assert_eq!(recipe[&column_naming.column_name(&["Filenames"])], json!(["chair"]));
+    }
+
+    #[test]
+    fn finds_well_known_columns_in_snake_case() {
+        finds_well_known_columns(spreadsheet::ColumnNaming::SnakeCase);
+    }
+
+    #[test]
+    fn finds_well_known_columns_in_camel_case() {
+        finds_well_known_columns(spreadsheet::ColumnNaming::CamelCase);
+    }
+
+    #[test]
+    fn finds_well_known_columns_verbatim() {
+        finds_well_known_columns(spreadsheet::ColumnNaming::Verbatim);
+    }
+
+    #[test]
+    fn finds_well_known_columns_of_linked_cells() {
+        let options = spreadsheet::JsonOptions {
+            links: spreadsheet::LinkMode::Object,
+            ..spreadsheet::JsonOptions::default()
+        };
+        let housewares = json_sheet_from_cells("Housewares", vec![
+            vec![text("Name"), text("Filename"), text("Image")],
+            vec![linked("Wooden Chair"), text("chair"), linked("https://example.com/chair.png")],
+        ], &options);
+        let recipes = json_sheet_from_cells("Recipes", vec![
+            vec![text("Name"), text("Category")],
+            vec![linked("Wooden Chair"), linked("Housewares")],
+        ], &options);
+
+        assert_eq!(housewares.rows[0]["name"], json!({ "text": "Wooden Chair", "url": "https://example.com" }));
+
+        let image = Image::from_row(&housewares, &housewares.rows[0]).unwrap();
+        assert_eq!((image.url, image.filename), ("https://example.com/chair.png", "chair"));
+
+        let mut datamine = Datamine(vec![
+            (housewares.title.clone(), housewares),
+            (recipes.title.clone(), recipes),
+        ].into_iter().collect());
+        datamine.assign_filenames_to_recipes(options.column_naming).unwrap();
+
+        assert_eq!(datamine["Recipes"].rows[0]["filenames"], json!(["chair"]));
+    }
+
+    #[test]
+    fn error_values_of_well_known_columns_are_null() {
+        assert_eq!(cell_text(&json!({ "error": "#N/A", "message": "Not found" })), &Value::Null);
+        assert_eq!(cell_text(&json!("Wooden Chair")), &json!("Wooden Chair"));
+    }
+
+    fn selects(args: &[&str], title: &str) -> bool {
+        let opt = Opt::from_iter(std::iter::once("datamine_exporter").chain(args.iter().copied()));
+        let properties = serde_json::from_value(json!({ "title": title, "sheetId": 1, "index": 0 })).unwrap();
+
+        opt.sheet_selector().matches_properties(&properties)
+    }
+
+    #[test]
+    fn always_excludes_read_me_by_default() {
+        assert!(!selects(&[], "Read Me"));
+        assert!(!selects(&["--exclude=*Old*"], "Read Me"));
+        assert!(!selects(&["--exclude=*Old*"], "Fish (Old)"));
+        assert!(selects(&["--exclude=*Old*"], "Fish"));
+    }
+
+    #[test]
+    fn read_me_can_be_included() {
+        assert!(selects(&["--include-read-me"], "Read Me"));
+        assert!(!selects(&["--include-read-me", "--exclude=Read*"], "Read Me"));
     }
 }
@@ -1,12 +1,33 @@
-use anyhow::*;
-use std::{borrow::Cow, ops::*};
-use serde::Deserialize;
+use anyhow::{bail, Context, Error, Result};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap, HashSet}, fmt, ops::*, str::FromStr};
+use serde::{Deserialize, Serialize};
 use serde_json as json;
-use lazy_static::lazy_static;
-use regex::Regex;
 
 pub mod client;
-pub use client::Client;
+pub use client::{Auth, CachePolicy, Client, ClientBuilder, ServiceAccount};
+
+pub mod error;
+pub use error::{CellError, DecodeError, DecodeErrorKind};
+
+pub mod formula;
+use formula::{Formula, ImageFormula};
+
+pub mod naming;
+pub use naming::ColumnNaming;
+
+pub mod format;
+pub use format::{CellFormat, FormatCondition, FormatFlag};
+
+mod stream;
+pub use stream::StreamedRow;
+
+pub mod selector;
+pub use selector::SheetSelector;
+pub mod sentinel;
+pub use sentinel::Sentinels;
+
+#[cfg(test)]
+mod tests;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all="camelCase")]
@@ -16,7 +37,7 @@ pub struct Spreadsheet {
 
 impl Spreadsheet {
     pub fn from_json_bytes(bytes: &[u8]) -> Result<Self> {
-        let spreadsheet = serde_json::from_slice::<Spreadsheet>(&bytes)
+        let spreadsheet = serde_json::from_slice::<Spreadsheet>(bytes)
             .context("Failed deserializing spreadsheet")?;
 
         Ok(spreadsheet)
@@ -29,6 +50,10 @@ impl Spreadsheet {
     pub fn find_sheet_by_title(&self, title: &str) -> Option<&Sheet> {
         self.sheets().find(|sheet| sheet.title() == title)
     }
+
+    pub fn select_sheets<'a>(&'a self, selector: &'a SheetSelector) -> impl Iterator<Item = &'a Sheet> {
+        self.sheets().filter(move |sheet| selector.matches(sheet))
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -36,6 +61,8 @@ impl Spreadsheet {
 pub struct Sheet {
     properties: SheetProperties,
     data: Vec<GridData>,
+    #[serde(default)]
+    merges: Vec<GridRange>,
 }
 
 impl Sheet {
@@ -43,38 +70,249 @@ impl Sheet {
         &self.properties.title
     }
 
-    fn grid_data(&self) -> Result<&GridData> {
-        let grid_data = self.data.first().context("No grid data")?;
-        Ok(grid_data)
+    /// The id of the sheet, also known as `gid`
+    pub fn sheet_id(&self) -> i64 {
+        self.properties.sheet_id
+    }
+
+    /// The position of the sheet within the spreadsheet
+    pub fn index(&self) -> usize {
+        self.properties.index
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.properties.hidden
+    }
+
+    pub fn grid_properties(&self) -> &GridProperties {
+        &self.properties.grid_properties
+    }
+
+    /// Returns the metadata of the sheet and its converted rows.
+    pub fn metadata(&self, json_rows: &JsonRows) -> SheetMetadata {
+        SheetMetadata {
+            title: self.title().to_owned(),
+            gid: self.sheet_id(),
+            index: self.index(),
+            hidden: self.is_hidden(),
+            columns: json_rows.columns.clone(),
+            row_count: json_rows.rows.len(),
+            grid_properties: self.grid_properties().clone(),
+            enums: json_rows.enums.clone(),
+        }
+    }
+
+    /// Returns all cells of the sheet as one grid.
+    pub fn grid(&self) -> Result<Grid<'_>> {
+        Grid::new(&self.data)
     }
 
     /// Returns the column titles (first row) in a normalized form:
     /// - lowercased
     /// - spaces are translated to `_`
     pub fn column_titles(&self) -> Result<Vec<String>> {
-        self.grid_data()?
-            .row_data.first().context("No column titles")?
-            .values.iter()
-            .map(|cell| cell
-                .to_string()
-                .map(|column| Self::normalize_column_name(&column))
-                .context("Empty column title")
-            )
+        self.column_titles_with(&Header::default())
+    }
+
+    /// Returns the normalized column titles of the given header.
+    ///
+    /// If the header spans multiple rows, the titles of all rows are joined
+    /// into compound titles like `nh_jan`.
+    /// Group titles merged across several columns apply to each of these columns.
+    pub fn column_titles_with(&self, header: &Header) -> Result<Vec<String>> {
+        self.optional_column_titles(header, ColumnNaming::Legacy)?
+            .into_iter()
+            .map(|title| title.context("Empty column title"))
             .collect()
     }
 
-    fn normalize_column_name(column: &str) -> String {
-        column
-        .chars()
-        .map(|c| match c {
-            ' ' => '_',
-            c => c.to_ascii_lowercase(),
+    /// Like `column_titles_with`, but blank titles are `None`.
+    fn optional_column_titles(&self, header: &Header, naming: ColumnNaming) -> Result<Vec<Option<String>>> {
+        let grid = self.grid()?;
+        let header_rows = self.header_rows(header);
+        let last_header_row = header_rows.end - 1;
+        let merged_cells = MergedCells::new(&self.merges);
+
+        if header_rows.start >= grid.rows().len() {
+            bail!("No column titles");
+        }
+
+        let width = header_rows.clone()
+            .map(|row| {
+                let cells = grid.rows().get(row).map(Vec::len).unwrap_or(0);
+                cells.max(merged_cells.row_width(row))
+            })
+            .max()
+            .unwrap_or(0);
+
+        (0..width)
+            .map(|i| {
+                let mut parts = Vec::new();
+
+                for row in header_rows.clone() {
+                    let (anchor_row, anchor_column) = match merged_cells.anchor(row, i) {
+                        Some(anchor) if row != last_header_row => anchor,
+                        _ => (row, i),
+                    };
+                    let cell = match grid.cell(anchor_row, anchor_column) {
+                        Some(cell) => cell,
+                        None => continue,
+                    };
+                    let title = cell.to_string()
+                        .map_err(|error| self.cell_error(row, error::column_letter(i), error))?;
+
+                    if let Some(title) = title.filter(|title| !title.trim().is_empty()) {
+                        parts.push(title);
+                    }
+                }
+
+                let title = naming.column_name(&parts);
+
+                if title.is_empty() {
+                    return Ok(None);
+                }
+
+                Ok(Some(title))
+            })
+            .collect()
+    }
+
+    /// Returns the keys used for the columns in json rows.
+    ///
+    /// Titles are normalized according to `options.column_naming`
+    /// and then renamed according to `options.column_renames`.
+    ///
+    /// Blank and duplicate titles are either rejected or disambiguated,
+    /// depending on `options.column_names`:
+    /// - blank titles are replaced with `_col_<n>`, where `n` is the one based column number
+    /// - duplicate titles get a `_2`, `_3`, ... suffix in column order
+    pub fn column_keys(&self, options: &JsonOptions) -> Result<Vec<String>> {
+        self.column_keys_with(
+            options.header_for(self.title()),
+            options.column_naming,
+            &options.column_renames,
+            options.column_names,
+        )
+    }
+
+    /// Returns the keys used for the columns in json rows,
+    /// keyed by the legacy name of the column (see `ColumnNaming::Legacy`), e.g. `unique_entry_id`.
+    ///
+    /// This finds well known columns regardless of `options.column_naming` and `options.column_renames`.
+    pub fn column_keys_by_legacy_name(&self, options: &JsonOptions) -> Result<HashMap<String, String>> {
+        let header = options.header_for(self.title());
+        let legacy_names = self.column_keys_with(header, ColumnNaming::Legacy, &HashMap::new(), ColumnNames::Suffix)?;
+        let keys = self.column_keys(options)?;
+
+        Ok(legacy_names.into_iter().zip(keys).collect())
+    }
+
+    fn column_keys_with(
+        &self,
+        header: &Header,
+        naming: ColumnNaming,
+        renames: &HashMap<String, String>,
+        column_names: ColumnNames,
+    ) -> Result<Vec<String>> {
+        let titles = self.optional_column_titles(header, naming)?
+            .into_iter()
+            .map(|title| title.map(|title| match renames.get(&title) {
+                Some(rename) => rename.clone(),
+                None => title,
+            }))
+            .collect::<Vec<_>>();
+        let mut keys = Vec::<String>::with_capacity(titles.len());
+        let mut taken = HashSet::new();
+
+        for (i, title) in titles.iter().enumerate() {
+            let title = match (title, column_names) {
+                (Some(title), _) => title.clone(),
+                (None, ColumnNames::Suffix) => blank_column_key(i),
+                (None, ColumnNames::Strict) => bail!(
+                    "Sheet '{}': Column {} has no title",
+                    self.title(),
+                    error::column_letter(i),
+                ),
+            };
+
+            let mut key = title.clone();
+
+            if taken.contains(&key) {
+                if column_names == ColumnNames::Strict {
+                    let first = keys.iter().position(|other| other == &key).unwrap_or_default();
+
+                    bail!(
+                        "Sheet '{}': Columns {} and {} have the same title '{}'",
+                        self.title(),
+                        error::column_letter(first),
+                        error::column_letter(i),
+                        title,
+                    );
+                }
+
+                key = (2..)
+                    .map(|n| format!("{}_{}", title, n))
+                    .find(|key| !taken.contains(key) && !titles.contains(&Some(key.clone())))
+                    .unwrap();
+            }
+
+            taken.insert(key.clone());
+            keys.push(key);
+        }
+
+        Ok(keys)
+    }
+
+    /// Returns the range of rows making up the header.
+    fn header_rows(&self, header: &Header) -> Range<usize> {
+        let frozen_row_count = self.properties.grid_properties.frozen_row_count;
+
+        if header.detect && frozen_row_count > header.row {
+            return header.row..frozen_row_count;
+        }
+
+        header.row..header.row + header.rows.max(1)
+    }
+
+    /// Returns the location of a row within the spreadsheet as json:
+    /// the spreadsheet id and url (if known), the gid of the sheet,
+    /// the one based row number and an A1 reference to the row.
+    fn source(&self, row_index: usize, spreadsheet_id: Option<&str>) -> json::Value {
+        let row = row_index + 1;
+        let a1 = format!("{}!A{}", sheet_range(self.title()), row);
+        let url = spreadsheet_id.map(|spreadsheet_id| format!(
+            "https://docs.google.com/spreadsheets/d/{}/edit#gid={}&range=A{}",
+            spreadsheet_id,
+            self.sheet_id(),
+            row,
+        ));
+
+        json::json!({
+            "spreadsheet_id": spreadsheet_id,
+            "gid": self.sheet_id(),
+            "row": row,
+            "a1": a1,
+            "url": url,
         })
-        .collect()
     }
 
+    fn cell_error(&self, row: usize, column: String, error: DecodeError) -> CellError {
+        CellError {
+            sheet: self.title().to_owned(),
+            row,
+            column,
+            error,
+        }
+    }
+
+    pub fn merges(&self) -> &[GridRange] {
+        &self.merges
+    }
+
+    /// Returns the rows of the first grid data block, without its first row.
+    #[deprecated(note = "Only covers the first grid data block, use `grid` instead")]
     pub fn rows(&self) -> Result<&[RowData]> {
-        let grid_data = self.grid_data()?;
+        let grid_data = self.data.first().context("No grid data")?;
 
         if grid_data.row_data.len() <= 1 {
             return Ok(&[]);
@@ -84,46 +322,733 @@ impl Sheet {
     }
 
     pub fn json_rows(&self) -> Result<Vec<json::Map<String, json::Value>>> {
-        let columns = self.column_titles()?;
-        let rows = self.grid_data()?
-            .row_data.iter().skip(1)
-            .map(|row| {
-                let mut map = json::Map::new();
+        let rows = self.json_rows_with(&JsonOptions::default())?;
+        Ok(rows.rows)
+    }
+
+    /// Returns the values allowed by the dropdowns (`ONE_OF_LIST` data validation rules)
+    /// of each column, keyed by column key.
+    /// Columns without such rules are omitted.
+    pub fn column_enums(&self, options: &JsonOptions) -> Result<BTreeMap<String, Vec<String>>> {
+        let header = options.header_for(self.title());
+        let columns = self.column_keys(options)?;
+        let first_data_row = self.header_rows(header).end;
+        let grid = self.grid()?;
+
+        Ok(Self::enums_of_grid(&grid, &columns, first_data_row))
+    }
+
+    fn enums_of_grid(grid: &Grid, columns: &[String], first_data_row: usize) -> BTreeMap<String, Vec<String>> {
+        let mut enums = BTreeMap::<String, Vec<String>>::new();
+
+        for row in grid.rows().iter().skip(first_data_row) {
+            for (i, cell) in row.iter().enumerate() {
+                let (key, cell) = match (columns.get(i), cell) {
+                    (Some(key), Some(cell)) => (key, cell),
+                    _ => continue,
+                };
+                let allowed_values = match cell.allowed_values() {
+                    Some(allowed_values) => allowed_values,
+                    None => continue,
+                };
+                let column_enum = enums.entry(key.clone()).or_default();
+
+                for value in allowed_values {
+                    if !column_enum.iter().any(|other| other == value) {
+                        column_enum.push(value.to_owned());
+                    }
+                }
+            }
+        }
+
+        enums
+    }
+
+    /// Converts the sheet to json rows.
+    ///
+    /// Cells that fail to decode abort the conversion with a `CellError`,
+    /// unless `options.strictness` is `Strictness::Lenient`.
+    /// In that case the cell is skipped and the error is returned as a warning.
+    pub fn json_rows_with(&self, options: &JsonOptions) -> Result<JsonRows> {
+        let header = options.header_for(self.title());
+        let columns = self.column_keys(options)?;
+        let first_data_row = self.header_rows(header).end;
+        let grid = self.grid()?;
+        let merged_cells = if options.fill_merged {
+            MergedCells::new(&self.merges)
+        } else {
+            MergedCells::default()
+        };
+        let enums = Self::enums_of_grid(&grid, &columns, first_data_row);
+        let mut derived_fields = DerivedFields::new(&columns);
+        let mut warnings = Vec::new();
+        let mut formula_errors = Vec::new();
+        let mut rows = Vec::new();
+
+        for (row_index, row) in grid.rows().iter().enumerate().skip(first_data_row) {
+            let mut map = json::Map::new();
+            let mut notes = json::Map::new();
+            let mut flags = json::Map::new();
+            // Merges may cover cells past the last cell of the row
+            let width = row.len().max(merged_cells.row_width(row_index));
+
+            for i in 0..width {
+                let anchor = merged_cells.anchor(row_index, i);
+                let cell = match anchor {
+                    Some((row, column)) => grid.cell(row, column),
+                    None => row.get(i).copied().flatten(),
+                };
+                let cell = match cell {
+                    Some(cell) => cell,
+                    None => continue,
+                };
+                let key = columns.get(i).cloned().unwrap_or_else(|| blank_column_key(i));
+                // Cells covered by a merge only get the value of the merge.
+                // Its notes, flags, errors and derived fields belong to the top left cell.
+                let covered = anchor.is_some();
+                // eprintln!("Key = {}", key);
 
-                for (i, cell) in row.values.iter().enumerate() {
-                    let key = columns.get(i).cloned().unwrap_or_default();
-                    // eprintln!("Key = {}", key);
-                    let value = cell.to_string()
+                if i >= columns.len() && options.column_names == ColumnNames::Strict {
+                    // Error values and cells that fail to decode count as values
+                    let is_blank = cell.error_value().is_none() && matches!(cell.to_string(), Ok(None));
+
+                    if is_blank {
+                        continue;
+                    }
+
+                    bail!(
+                        "Sheet '{}': Cell {}{} has a value, but its column has no title",
+                        self.title(),
+                        error::column_letter(i),
+                        row_index + 1,
+                    );
+                }
+
+                if let Some(note) = cell.note().filter(|_| options.notes && !covered) {
+                    notes.insert(key.clone(), note.into());
+                }
+
+                if !covered {
+                    let cell_flags = cell.flags(&options.format_flags).collect::<Vec<_>>();
+
+                    if !cell_flags.is_empty() {
+                        flags.insert(key.clone(), cell_flags.into());
+                    }
+                }
+
+                if let Some(error_value) = cell.error_value() {
+                    if !covered {
+                        formula_errors.push(FormulaError {
+                            row: row_index,
+                            column: key.clone(),
+                            formula: cell.formula().map(str::to_owned),
+                            error: error_value.clone(),
+                        });
+                    }
+
+                    map.insert(key, options.error_values.to_json(error_value));
+                    continue;
+                }
+
+                let value = if options.typed_values {
+                    cell.to_json_value()
+                } else {
+                    cell.to_string().map(|value| value
                         .map(Cow::into_owned)
                         .map(json::Value::String)
-                        .unwrap_or(json::Value::Null);
-                    // eprintln!("Value = {}", value);
+                        .unwrap_or(json::Value::Null)
+                    )
+                };
+                // eprintln!("Value = {}", value);
+
+                let mut value = match value {
+                    Ok(value) => value,
+                    Err(error) => {
+                        let error = self.cell_error(row_index, key, error);
+
+                        match options.strictness {
+                            Strictness::Strict => return Err(error.into()),
+                            Strictness::Lenient => {
+                                if !covered {
+                                    warnings.push(error);
+                                }
 
-                    map.insert(key, value.into());
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                if let json::Value::String(text) = &value {
+                    if let Some(replacement) = options.sentinels.replacement(&key, text) {
+                        value = replacement.clone();
+                    }
                 }
 
-                map
-            })
+                if let Some(value) = cell.disallowed_value().filter(|_| options.validate_enums && !covered) {
+                    let error = DecodeError::new(DecodeErrorKind::NotAllowed, value);
+                    let error = self.cell_error(row_index, key.clone(), error);
+
+                    match options.strictness {
+                        Strictness::Strict => return Err(error.into()),
+                        Strictness::Lenient => warnings.push(error),
+                    }
+                }
+
+                let mut derived = Vec::new();
+
+                if let Some(image) = cell.image().filter(|_| options.image_metadata) {
+                    derived.push(("image_meta", image.metadata()));
+                }
+
+                if let Some(url) = cell.hyperlink() {
+                    match options.links {
+                        LinkMode::Ignore => {},
+                        LinkMode::Object => {
+                            value = json::json!({
+                                "text": value,
+                                "url": url,
+                            });
+                        },
+                        LinkMode::Sibling => derived.push(("url", url.into())),
+                    }
+                }
+
+                if !covered {
+                    for (suffix, derived_value) in derived {
+                        if let Err(error) = derived_fields.insert(&mut map, &key, suffix, derived_value) {
+                            warnings.push(self.cell_error(row_index, key.clone(), error));
+                        }
+                    }
+                }
+
+                map.insert(key, value);
+            }
+
             // Only keep rows with data
-            .filter(|row| row.iter().any(|(_key, value)| value != &json::Value::Null))
-            .collect();
+            if map.values().all(|value| value == &json::Value::Null) {
+                continue;
+            }
+
+            if !notes.is_empty() {
+                map.insert("_notes".into(), notes.into());
+            }
+
+            if !flags.is_empty() {
+                map.insert("_flags".into(), flags.into());
+            }
+
+            if options.source {
+                map.insert("_source".into(), self.source(row_index, options.spreadsheet_id.as_deref()));
+            }
+
+            rows.push(map);
+        }
+
+        Ok(JsonRows {
+            rows,
+            columns,
+            enums,
+            warnings,
+            formula_errors,
+        })
+    }
+}
+
+/// Suffixes of the fields derived from a cell, like `<column>_url`
+const DERIVED_SUFFIXES: [&str; 2] = ["image_meta", "url"];
+
+/// Inserts fields derived from a cell, like `<column>_url`, next to the cell's value.
+struct DerivedFields<'a> {
+    /// Keys of all columns, which derived fields must not replace
+    columns: HashSet<&'a str>,
+    /// Derived keys that collided with a column and have been reported
+    collisions: HashSet<String>,
+}
+
+impl<'a> DerivedFields<'a> {
+    fn new(columns: &'a [String]) -> Self {
+        Self {
+            columns: columns.iter().map(String::as_str).collect(),
+            collisions: HashSet::new(),
+        }
+    }
+
+    /// Inserts `value` as `<column>_<suffix>` into `row`.
+    ///
+    /// If a column has the same key, the value is skipped instead.
+    /// The first skipped value of every key is returned as an error.
+    fn insert(
+        &mut self,
+        row: &mut json::Map<String, json::Value>,
+        column: &str,
+        suffix: &str,
+        value: json::Value,
+    ) -> Result<(), DecodeError> {
+        let key = format!("{}_{}", column, suffix);
+
+        if !self.columns.contains(key.as_str()) {
+            row.insert(key, value);
+            return Ok(());
+        }
+
+        if self.collisions.insert(key.clone()) {
+            return Err(DecodeError::new(DecodeErrorKind::KeyCollision, key));
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes the keys of a row in spreadsheet column order.
+///
+/// Fields derived from a column, like `<column>_url`, follow the column.
+/// All other fields, like `_notes`, come last in alphabetical order.
+pub struct OrderedRow<'a> {
+    row: &'a json::Map<String, json::Value>,
+    columns: &'a [String],
+}
+
+impl<'a> OrderedRow<'a> {
+    /// `columns` are the column keys in spreadsheet order, see `JsonRows::columns`.
+    pub fn new(row: &'a json::Map<String, json::Value>, columns: &'a [String]) -> Self {
+        Self { row, columns }
+    }
+}
+
+impl Serialize for OrderedRow<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let columns = self.columns.iter().map(String::as_str).collect::<HashSet<_>>();
+        let mut written = HashSet::new();
+        let mut map = serializer.serialize_map(Some(self.row.len()))?;
+
+        for column in self.columns {
+            let derived = DERIVED_SUFFIXES.iter()
+                .map(|suffix| format!("{}_{}", column, suffix))
+                .filter(|key| !columns.contains(key.as_str()));
+
+            for key in std::iter::once(column.clone()).chain(derived) {
+                if let Some(value) = self.row.get(&key) {
+                    map.serialize_entry(&key, value)?;
+                    written.insert(key);
+                }
+            }
+        }
+
+        for (key, value) in self.row {
+            if !written.contains(key) {
+                map.serialize_entry(key, value)?;
+            }
+        }
+
+        map.end()
+    }
+}
+
+/// The result of converting a sheet to json rows.
+#[derive(Debug, Default)]
+pub struct JsonRows {
+    pub rows: Vec<json::Map<String, json::Value>>,
+    /// Column keys in spreadsheet order
+    pub columns: Vec<String>,
+    /// Values allowed by the dropdowns of each column
+    pub enums: BTreeMap<String, Vec<String>>,
+    /// Errors of cells that were skipped or failed validation
+    pub warnings: Vec<CellError>,
+    /// Every cell whose value is a spreadsheet error like `#N/A` or `#REF!`
+    pub formula_errors: Vec<FormulaError>,
+}
+
+/// A cell that evaluated to a spreadsheet error.
+#[derive(Serialize, Debug, Clone)]
+pub struct FormulaError {
+    /// Zero based index of the row within the sheet
+    pub row: usize,
+    pub column: String,
+    pub formula: Option<String>,
+    pub error: ErrorValue,
+}
+
+/// Options for converting a sheet to json rows.
+#[derive(Debug, Clone, Default)]
+pub struct JsonOptions {
+    /// Emit numbers and booleans as json numbers and booleans,
+    /// based on the effective value of a cell.
+    /// Otherwise every value is exported as a string.
+    pub typed_values: bool,
+    /// How to handle cells that fail to decode
+    pub strictness: Strictness,
+    /// How to export cells containing spreadsheet errors
+    pub error_values: ErrorValueMode,
+    /// How to export hyperlinks of cells
+    pub links: LinkMode,
+    /// Collect the notes of each row into a `_notes` map keyed by column
+    pub notes: bool,
+    /// Add a `<column>_image_meta` field with the mode and size arguments
+    /// next to cells containing an `IMAGE` formula.
+    /// If another column has that key, the field is skipped with a warning.
+    pub image_metadata: bool,
+    /// Copy the value of merged cells into every cell covered by the merge.
+    /// Notes, flags, formula errors and derived fields are only exported for the top left cell.
+    pub fill_merged: bool,
+    /// Location of the header in every sheet
+    pub header: Header,
+    /// Location of the header in specific sheets, by sheet title
+    pub sheet_headers: HashMap<String, Header>,
+    /// How to handle blank and duplicate column titles
+    pub column_names: ColumnNames,
+    /// How to turn column titles into keys
+    pub column_naming: ColumnNaming,
+    /// Explicit names for columns, keyed by the normalized column name
+    pub column_renames: HashMap<String, String>,
+    /// Keep the keys of rows in spreadsheet column order instead of sorting them alphabetically.
+    /// Rows are always sorted, this only tells the exporter to serialize them as `OrderedRow`.
+    pub keep_column_order: bool,
+    /// Flags to set for formatted cells.
+    /// The flags of each row are collected into a `_flags` map keyed by column.
+    pub format_flags: Vec<FormatFlag>,
+    /// Check that the values of cells with a dropdown are allowed by their dropdown.
+    /// Invalid values are handled according to `strictness`,
+    /// but are kept in lenient mode.
+    pub validate_enums: bool,
+    /// Add a `_source` field to each row, describing where it comes from
+    pub source: bool,
+    /// Id of the spreadsheet, used for `_source`
+    pub spreadsheet_id: Option<String>,
+    /// Replacements for placeholder strings like `NA`
+    pub sentinels: Sentinels,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColumnNames {
+    /// Fail on blank or duplicate column titles and on values in columns without title
+    Strict,
+    /// Name blank columns `_col_<n>` and append `_2`, `_3`, ... to duplicate titles
+    #[default]
+    Suffix,
+}
+
+impl FromStr for ColumnNames {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "strict" => Ok(ColumnNames::Strict),
+            "suffix" => Ok(ColumnNames::Suffix),
+            _ => bail!("Unknown column name mode '{}', expected 'strict' or 'suffix'", mode),
+        }
+    }
+}
+
+/// Returns the A1 notation of a whole sheet, e.g. `'Wall-mounted'`.
+pub fn sheet_range(title: &str) -> String {
+    format!("'{}'", title.replace('\'', "''"))
+}
+
+fn blank_column_key(index: usize) -> String {
+    format!("_col_{}", index + 1)
+}
+
+impl JsonOptions {
+    pub fn header_for(&self, sheet_title: &str) -> &Header {
+        self.sheet_headers.get(sheet_title).unwrap_or(&self.header)
+    }
+
+    /// Returns a `fields` mask for the Sheets API,
+    /// which requests only what is needed to convert sheets with these options.
+    pub fn fields_mask(&self) -> String {
+        // Dropdowns are always needed for the enums of a sheet
+        let mut cell_fields = vec!["userEnteredValue", "effectiveValue", "dataValidation(condition)"];
+
+        if self.links != LinkMode::Ignore {
+            cell_fields.extend(&["hyperlink", "textFormatRuns(format(link))"]);
+        }
 
-        Ok(rows)
+        if self.notes {
+            cell_fields.push("note");
+        }
+
+        if !self.format_flags.is_empty() {
+            cell_fields.push("effectiveFormat(backgroundColor,textFormat(bold,strikethrough))");
+        }
+
+        format!(
+            "sheets(properties(title,sheetId,index,hidden,gridProperties),merges,data(startRow,startColumn,rowData(values({}))))",
+            cell_fields.join(","),
+        )
+    }
+}
+
+/// Location of the column titles within a sheet.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Header {
+    /// Zero based index of the first header row
+    pub row: usize,
+    /// Number of header rows
+    pub rows: usize,
+    /// Use the frozen rows of the sheet as header, starting at `row`.
+    /// Falls back to `rows` if the sheet has no frozen rows past `row`.
+    pub detect: bool,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self {
+            row: 0,
+            rows: 1,
+            detect: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkMode {
+    /// Only export the text of linked cells
+    #[default]
+    Ignore,
+    /// Export linked cells as `{ "text": ..., "url": ... }`
+    Object,
+    /// Add a `<column>_url` field next to linked cells.
+    /// If another column has that key, the link is skipped with a warning.
+    Sibling,
+}
+
+impl FromStr for LinkMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "ignore" => Ok(LinkMode::Ignore),
+            "object" => Ok(LinkMode::Object),
+            "sibling" => Ok(LinkMode::Sibling),
+            _ => bail!("Unknown link mode '{}', expected 'ignore', 'object' or 'sibling'", mode),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorValueMode {
+    /// Export errors as `null`
+    #[default]
+    Null,
+    /// Export errors as `{ "error": "#N/A", "message": "..." }`
+    Tagged,
+}
+
+impl ErrorValueMode {
+    fn to_json(self, error_value: &ErrorValue) -> json::Value {
+        match self {
+            ErrorValueMode::Null => json::Value::Null,
+            ErrorValueMode::Tagged => json::json!({
+                "error": error_value.error_type.to_string(),
+                "message": error_value.message,
+            }),
+        }
     }
 }
 
+impl FromStr for ErrorValueMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "null" => Ok(ErrorValueMode::Null),
+            "tagged" => Ok(ErrorValueMode::Tagged),
+            _ => bail!("Unknown error value mode '{}', expected 'null' or 'tagged'", mode),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// Fail on the first cell that can't be decoded
+    #[default]
+    Strict,
+    /// Skip cells that can't be decoded and record a warning
+    Lenient,
+}
+
+/// Metadata of a sheet and its converted rows.
+#[derive(Serialize, Debug, Clone)]
+pub struct SheetMetadata {
+    pub title: String,
+    pub gid: i64,
+    /// Position of the sheet within the spreadsheet
+    pub index: usize,
+    pub hidden: bool,
+    /// Column keys in spreadsheet order
+    pub columns: Vec<String>,
+    /// Number of exported rows
+    pub row_count: usize,
+    pub grid_properties: GridProperties,
+    /// Values allowed by the dropdowns of each column
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub enums: BTreeMap<String, Vec<String>>,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all="camelCase")]
 pub struct SheetProperties {
     title: String,
+    #[serde(default)]
+    sheet_id: i64,
+    #[serde(default)]
+    index: usize,
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    grid_properties: GridProperties,
+}
+
+impl SheetProperties {
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn sheet_id(&self) -> i64 {
+        self.sheet_id
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all(deserialize="camelCase"), default)]
+pub struct GridProperties {
+    pub row_count: usize,
+    pub column_count: usize,
+    pub frozen_row_count: usize,
+    pub frozen_column_count: usize,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all="camelCase")]
 pub struct GridData {
+    #[serde(default)]
+    start_row: usize,
+    #[serde(default)]
+    start_column: usize,
+    #[serde(default)]
     row_data: Vec<RowData>,
 }
 
+/// The cells of all `GridData` blocks of a sheet, placed at their offsets.
+#[derive(Debug)]
+pub struct Grid<'a> {
+    rows: Vec<Vec<Option<&'a CellData>>>,
+}
+
+impl<'a> Grid<'a> {
+    /// Stitches the blocks together.
+    /// If blocks overlap, cells of later blocks take precedence.
+    pub fn new(blocks: &'a [GridData]) -> Result<Self> {
+        if blocks.is_empty() {
+            bail!("No grid data");
+        }
+
+        let mut rows = Vec::<Vec<Option<&CellData>>>::new();
+
+        for block in blocks {
+            for (row_offset, row) in block.row_data.iter().enumerate() {
+                let row_index = block.start_row + row_offset;
+
+                if rows.len() <= row_index {
+                    rows.resize_with(row_index + 1, Vec::new);
+                }
+
+                let grid_row = &mut rows[row_index];
+
+                for (column_offset, cell) in row.values.iter().enumerate() {
+                    let column_index = block.start_column + column_offset;
+
+                    if grid_row.len() <= column_index {
+                        grid_row.resize(column_index + 1, None);
+                    }
+
+                    grid_row[column_index] = Some(cell);
+                }
+            }
+        }
+
+        Ok(Self {
+            rows,
+        })
+    }
+
+    /// Returns all rows. Rows may have different lengths and contain missing cells.
+    pub fn rows(&self) -> &[Vec<Option<&'a CellData>>] {
+        &self.rows
+    }
+
+    pub fn cell(&self, row: usize, column: usize) -> Option<&'a CellData> {
+        *self.rows.get(row)?.get(column)?
+    }
+}
+
+/// A range of cells. Missing start indices are 0, missing end indices are unbounded.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all="camelCase")]
+pub struct GridRange {
+    pub sheet_id: Option<i64>,
+    #[serde(default)]
+    pub start_row_index: usize,
+    pub end_row_index: Option<usize>,
+    #[serde(default)]
+    pub start_column_index: usize,
+    pub end_column_index: Option<usize>,
+}
+
+/// Lookup of the cells covered by merges.
+#[derive(Debug, Default)]
+struct MergedCells {
+    /// Maps every covered cell to the top left cell of its merge.
+    /// The top left cells themselves are not included.
+    anchors: HashMap<(usize, usize), (usize, usize)>,
+    /// Number of columns of each row that are covered by merges
+    row_widths: HashMap<usize, usize>,
+}
+
+impl MergedCells {
+    fn new(merges: &[GridRange]) -> Self {
+        let mut merged_cells = Self::default();
+
+        for merge in merges {
+            // Merges are always bounded
+            let (end_row, end_column) = match (merge.end_row_index, merge.end_column_index) {
+                (Some(end_row), Some(end_column)) => (end_row, end_column),
+                _ => continue,
+            };
+            let anchor = (merge.start_row_index, merge.start_column_index);
+
+            for row in merge.start_row_index..end_row {
+                for column in merge.start_column_index..end_column {
+                    if (row, column) != anchor {
+                        merged_cells.anchors.insert((row, column), anchor);
+                    }
+                }
+
+                let width = merged_cells.row_widths.entry(row).or_default();
+                *width = (*width).max(end_column);
+            }
+        }
+
+        merged_cells
+    }
+
+    fn anchor(&self, row: usize, column: usize) -> Option<(usize, usize)> {
+        self.anchors.get(&(row, column)).copied()
+    }
+
+    fn row_width(&self, row: usize) -> usize {
+        self.row_widths.get(&row).copied().unwrap_or(0)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all="camelCase")]
 pub struct RowData {
@@ -139,27 +1064,34 @@ impl Deref for RowData {
     }
 }
 
-lazy_static! {
-    static ref IMAGE_FORMULA_RE: Regex =
-        Regex::new(r#"(?i)=IMAGE\("(.*)"\)"#).unwrap();
-}
-
 #[derive(Deserialize, Debug)]
 #[serde(rename_all="camelCase")]
 pub struct CellData {
     user_entered_value: Option<ExtendedValue>,
     effective_value: Option<ExtendedValue>,
+    hyperlink: Option<String>,
+    #[serde(default)]
+    text_format_runs: Vec<TextFormatRun>,
+    note: Option<String>,
+    effective_format: Option<CellFormat>,
+    data_validation: Option<DataValidationRule>,
 }
 
 impl CellData {
-    pub fn to_string(&self) -> Option<Cow<str>> {
+    pub fn to_string(&self) -> Result<Option<Cow<'_, str>>, DecodeError> {
         if let Some(effective_value) = &self.effective_value {
             match effective_value {
-                ExtendedValue::String { value } => return Some(Cow::Borrowed(value)),
-                ExtendedValue::Number { value } => return Some(value.to_string().into()),
+                ExtendedValue::String { value } => return Ok(Some(Cow::Borrowed(value))),
+                ExtendedValue::Number { value } => return Ok(Some(value.to_string().into())),
+                ExtendedValue::Bool { value } => return Ok(Some(value.to_string().into())),
                 // In this case, fall back to user_entered_value
                 ExtendedValue::Empty {} => {},
-                _ => unimplemented!("other effective value type: {:?}", effective_value),
+                ExtendedValue::Formula { .. } => {
+                    return Err(DecodeError::new(DecodeErrorKind::UnsupportedValue, effective_value));
+                },
+                ExtendedValue::Error { .. } => {
+                    return Err(DecodeError::new(DecodeErrorKind::ErrorValue, effective_value));
+                },
             }
         }
 
@@ -167,18 +1099,202 @@ impl CellData {
             match user_entered_value {
                 ExtendedValue::Formula { value } => {
                     // eprintln!("Formula: {}", value);
-                    let caps = IMAGE_FORMULA_RE.captures(value).expect("image formula expected");
-                    let image_url = caps.get(1).unwrap().as_str();
+                    let formula = formula::parse(value)
+                        .map_err(|_| DecodeError::new(DecodeErrorKind::UnsupportedFormula, user_entered_value))?;
 
-                    return Some(Cow::Owned(image_url.to_string()));
+                    return Ok(match formula {
+                        Some(Formula::Image(image)) => Some(image.url),
+                        Some(Formula::Hyperlink { url, label }) => Some(label.unwrap_or(url)),
+                        // Any other formula evaluated to an empty effective value
+                        None => None,
+                    });
+                },
+                ExtendedValue::String { value } => return Ok(Some(Cow::Borrowed(value))),
+                ExtendedValue::Number { value } => return Ok(Some(value.to_string().into())),
+                ExtendedValue::Bool { value } => return Ok(Some(value.to_string().into())),
+                ExtendedValue::Error { .. } => {
+                    return Err(DecodeError::new(DecodeErrorKind::ErrorValue, user_entered_value));
                 },
-                ExtendedValue::Empty {} => return None,
-                _ => unimplemented!("other user entered value type: {:?}", user_entered_value),
+                ExtendedValue::Empty {} => return Ok(None),
             }
         }
 
-        None
+        Ok(None)
+    }
+
+    /// Returns the value of the cell as json, typed by its effective value:
+    /// - numbers become json numbers (integral numbers are exported without a fraction)
+    /// - booleans become json booleans
+    /// - everything else is exported like `to_string`
+    pub fn to_json_value(&self) -> Result<json::Value, DecodeError> {
+        let value = match &self.effective_value {
+            Some(ExtendedValue::Number { value }) => number_to_json(*value),
+            Some(ExtendedValue::Bool { value }) => json::Value::Bool(*value),
+            _ => self.to_string()?
+                .map(Cow::into_owned)
+                .map(json::Value::String)
+                .unwrap_or(json::Value::Null),
+        };
+
+        Ok(value)
+    }
+
+    /// Returns the error of the cell if it evaluated to one, e.g. `#N/A`.
+    pub fn error_value(&self) -> Option<&ErrorValue> {
+        match &self.effective_value {
+            Some(ExtendedValue::Error { value }) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the link of the cell.
+    /// If the cell itself isn't linked, the first link within its text
+    /// or the url of a `HYPERLINK` formula is returned.
+    pub fn hyperlink(&self) -> Option<Cow<'_, str>> {
+        if let Some(link) = self.hyperlink.as_deref().or_else(|| self.links().next()) {
+            return Some(Cow::Borrowed(link));
+        }
+
+        match formula::parse(self.formula()?) {
+            Ok(Some(Formula::Hyperlink { url, .. })) => Some(url),
+            _ => None,
+        }
+    }
+
+    /// Returns the arguments of the cell's `IMAGE` formula, if any.
+    pub fn image(&self) -> Option<ImageFormula<'_>> {
+        match formula::parse(self.formula()?) {
+            Ok(Some(Formula::Image(image))) => Some(image),
+            _ => None,
+        }
+    }
+
+    /// Returns the links of all rich text runs within the cell.
+    pub fn links(&self) -> impl Iterator<Item = &str> {
+        self.text_format_runs.iter()
+            .filter_map(|run| run.format.link.as_ref())
+            .map(|link| link.uri.as_str())
+    }
+
+    /// Returns the format of the cell as it is displayed.
+    pub fn effective_format(&self) -> Option<&CellFormat> {
+        self.effective_format.as_ref()
+    }
+
+    /// Returns the names of all flags whose condition matches the cell's format.
+    pub fn flags<'a>(&'a self, format_flags: &'a [FormatFlag]) -> impl Iterator<Item = &'a str> {
+        format_flags.iter()
+            .filter(move |format_flag| match self.effective_format() {
+                Some(format) => format_flag.condition.matches(format),
+                None => false,
+            })
+            .map(|format_flag| format_flag.flag.as_str())
+    }
+
+    /// Returns the values allowed by the cell's dropdown,
+    /// if it has a `ONE_OF_LIST` data validation rule.
+    pub fn allowed_values(&self) -> Option<impl Iterator<Item = &str>> {
+        let condition = &self.data_validation.as_ref()?.condition;
+
+        if condition.condition_type != "ONE_OF_LIST" {
+            return None;
+        }
+
+        let values = condition.values.iter()
+            .filter_map(|value| value.user_entered_value.as_deref());
+
+        Some(values)
+    }
+
+    /// Returns the value entered into the cell if the cell's own dropdown doesn't allow it.
+    ///
+    /// Numbers and booleans are compared by value, so `1` is allowed by a dropdown value `1.0`
+    /// and `true` by `TRUE`. Cells without a `ONE_OF_LIST` rule and formulas are never reported.
+    pub fn disallowed_value(&self) -> Option<&ExtendedValue> {
+        let value = self.user_entered_value.as_ref()?;
+        let mut allowed_values = self.allowed_values()?;
+
+        let allowed = match value {
+            ExtendedValue::String { value } => allowed_values.any(|allowed| allowed == value),
+            ExtendedValue::Number { value } => allowed_values
+                .any(|allowed| allowed.trim().parse::<f64>() == Ok(*value)),
+            ExtendedValue::Bool { value } => allowed_values
+                .any(|allowed| allowed.trim().eq_ignore_ascii_case(&value.to_string())),
+            _ => true,
+        };
+
+        Some(value).filter(|_| !allowed)
+    }
+
+    /// Returns the note attached to the cell, if any.
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    /// Returns the formula of the cell, if any.
+    pub fn formula(&self) -> Option<&str> {
+        match &self.user_entered_value {
+            Some(ExtendedValue::Formula { value }) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+pub struct DataValidationRule {
+    condition: BooleanCondition,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+pub struct BooleanCondition {
+    #[serde(rename="type")]
+    condition_type: String,
+    #[serde(default)]
+    values: Vec<ConditionValue>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+pub struct ConditionValue {
+    user_entered_value: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+pub struct TextFormatRun {
+    #[serde(default)]
+    format: TextFormat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all="camelCase")]
+pub struct TextFormat {
+    link: Option<Link>,
+    pub bold: Option<bool>,
+    pub strikethrough: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+pub struct Link {
+    #[serde(default)]
+    uri: String,
+}
+
+fn number_to_json(value: f64) -> json::Value {
+    let is_integral = value.fract() == 0.0
+        && value >= i64::MIN as f64
+        && value <= i64::MAX as f64;
+
+    if is_integral {
+        return json::Value::from(value as i64);
     }
+
+    json::Number::from_f64(value)
+        .map(json::Value::Number)
+        .unwrap_or(json::Value::Null)
 }
 
 // #[derive(Deserialize, Debug)]
@@ -210,6 +1326,67 @@ pub enum ExtendedValue {
         #[serde(rename="formulaValue")]
         value: String,
     },
-    // error_value: Option<ErrorValue>,
+    Error {
+        #[serde(rename="errorValue")]
+        value: ErrorValue,
+    },
     Empty {},
 }
+
+/// Formats the value as it was entered or displayed, e.g. the formula text or `#N/A`.
+impl fmt::Display for ExtendedValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtendedValue::Number { value } => write!(f, "{}", value),
+            ExtendedValue::String { value } => write!(f, "{}", value),
+            ExtendedValue::Bool { value } => write!(f, "{}", value),
+            ExtendedValue::Formula { value } => write!(f, "{}", value),
+            ExtendedValue::Error { value } => write!(f, "{}", value.error_type),
+            ExtendedValue::Empty {} => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all="camelCase")]
+pub struct ErrorValue {
+    #[serde(rename="type")]
+    pub error_type: ErrorType,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="SCREAMING_SNAKE_CASE")]
+pub enum ErrorType {
+    Error,
+    NullValue,
+    DivideByZero,
+    Value,
+    Ref,
+    Name,
+    Num,
+    NA,
+    Loading,
+    #[serde(other)]
+    ErrorTypeUnspecified,
+}
+
+impl fmt::Display for ErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            ErrorType::Error => "#ERROR!",
+            ErrorType::NullValue => "#NULL!",
+            ErrorType::DivideByZero => "#DIV/0!",
+            ErrorType::Value => "#VALUE!",
+            ErrorType::Ref => "#REF!",
+            ErrorType::Name => "#NAME?",
+            ErrorType::Num => "#NUM!",
+            ErrorType::NA => "#N/A",
+            ErrorType::Loading => "Loading...",
+            ErrorType::ErrorTypeUnspecified => "#ERROR!",
+        };
+
+        write!(f, "{}", text)
+    }
+}
@@ -1,29 +1,223 @@
-use anyhow::*;
+use anyhow::{bail, Context, Result};
 use reqwest::Url;
-use std::{fs, path::*};
-use super::Spreadsheet;
+use serde::{Deserialize, Serialize};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{convert::TryFrom, fs, io::{self, BufReader, Read, Write}, path::*, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::io::AsyncWriteExt;
+use futures::{future::{Future, FutureExt}, stream::{self, StreamExt}};
+use super::{sheet_range, Sheet, SheetProperties, SheetSelector, Spreadsheet};
 
-const DOWNLOAD_BUF_CAPACITY: usize = 1024 * 1024 * 1024;
+pub mod retry;
+pub use retry::RetryPolicy;
+pub mod auth;
+pub use auth::{Auth, ServiceAccount};
+
+pub const SHEETS_API_URL: &str = "https://sheets.googleapis.com/v4/spreadsheets/";
+pub const DRIVE_API_URL: &str = "https://www.googleapis.com/drive/v3/files/";
 
 #[derive(Clone)]
 pub struct Client {
-    api_key: String,
+    auth: Arc<Auth>,
     cache_dir: PathBuf,
+    cache_policy: CachePolicy,
+    retry_policy: RetryPolicy,
+    sheets_url: Url,
+    drive_url: Url,
+    fields: Option<String>,
+    ranges: Vec<String>,
+    stale_if_error: bool,
+}
+
+/// Builds a `Client` with non-default settings.
+#[derive(Clone)]
+pub struct ClientBuilder {
+    client: Client,
+}
+
+impl ClientBuilder {
+    /// Directory to cache spreadsheets in, `cache` by default
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.client.cache_dir = cache_dir.into();
+        self
+    }
+
+    pub fn cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.client.cache_policy = cache_policy;
+        self
+    }
+
+    /// Use a cached spreadsheet if it can't be checked for modifications,
+    /// instead of failing. Off by default.
+    pub fn stale_if_error(mut self, stale_if_error: bool) -> Self {
+        self.client.stale_if_error = stale_if_error;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.client.retry_policy = retry_policy;
+        self
+    }
+
+    /// Base url of the Sheets API spreadsheets endpoint, `SHEETS_API_URL` by default
+    pub fn sheets_url(mut self, url: Url) -> Self {
+        self.client.sheets_url = directory_url(url);
+        self
+    }
+
+    /// Base url of the Drive API files endpoint, used for checking whether a spreadsheet changed.
+    /// `DRIVE_API_URL` by default.
+    pub fn drive_url(mut self, url: Url) -> Self {
+        self.client.drive_url = directory_url(url);
+        self
+    }
+
+    /// Only request these fields of the spreadsheet, see `JsonOptions::fields_mask`.
+    /// By default everything is requested.
+    pub fn fields(mut self, fields: impl Into<String>) -> Self {
+        self.client.fields = Some(fields.into());
+        self
+    }
+
+    /// Only request these ranges in A1 notation, e.g. whole sheets (see `sheet_range`).
+    /// By default all sheets are requested.
+    pub fn ranges(mut self, ranges: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.client.ranges = ranges.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn build(self) -> Client {
+        self.client
+    }
+}
+
+/// Appends a `/` to the path of `url` if it is missing,
+/// so that joining a spreadsheet id appends it instead of replacing the last segment.
+fn directory_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+
+    url
+}
+
+/// When to use a cached spreadsheet instead of downloading it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    /// Use the cache unless the spreadsheet has been modified since it was downloaded
+    #[default]
+    Revalidate,
+    /// Use the cache without checking for modifications while it is younger than the given age,
+    /// revalidate it afterwards
+    MaxAge(Duration),
+    /// Always download the spreadsheet
+    Refresh,
+}
+
+/// Describes a cached spreadsheet, stored as `<id>.meta.json` next to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct CacheMetadata {
+    /// Seconds since the unix epoch at which the download started
+    fetched_at: u64,
+    #[serde(flatten)]
+    revision: Revision,
+}
+
+impl CacheMetadata {
+    fn age(&self) -> Duration {
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(self.fetched_at);
+
+        SystemTime::now().duration_since(fetched_at).unwrap_or_default()
+    }
+}
+
+/// The revision of a spreadsheet according to the Drive API.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all="camelCase")]
+struct Revision {
+    modified_time: Option<String>,
+    version: Option<String>,
+}
+
+impl Revision {
+    fn is_known(&self) -> bool {
+        self.modified_time.is_some() || self.version.is_some()
+    }
+
+    /// Returns whether the spreadsheet was last modified before `time`, in seconds since the unix epoch.
+    /// This relies on the local clock, so it is only used if no earlier revision is known.
+    fn modified_before(&self, time: u64) -> bool {
+        matches!(self.modified_time.as_deref().and_then(unix_time), Some(modified_time) if modified_time < time)
+    }
+}
+
+/// Parses a UTC timestamp like `2020-06-01T09:30:00.123Z`, as returned by the Drive API,
+/// into whole seconds since the unix epoch.
+fn unix_time(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':').map(|part| part.split('.').next().unwrap_or_default().parse::<u64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
 }
 
 impl Client {
-    pub fn new(api_key: impl Into<String>, cache_dir: impl Into<PathBuf>) -> Self {
-        Self {
-            api_key: api_key.into(),
-            cache_dir: cache_dir.into(),
+    pub fn new(auth: impl Into<Auth>, cache_dir: impl Into<PathBuf>) -> Self {
+        Self::builder(auth)
+            .cache_dir(cache_dir)
+            .build()
+    }
+
+    /// `auth` is either an api key or a `ServiceAccount`.
+    pub fn builder(auth: impl Into<Auth>) -> ClientBuilder {
+        ClientBuilder {
+            client: Self {
+                auth: Arc::new(auth.into()),
+                cache_dir: "cache".into(),
+                cache_policy: CachePolicy::default(),
+                retry_policy: RetryPolicy::default(),
+                sheets_url: Url::parse(SHEETS_API_URL).expect("invalid SHEETS_API_URL"),
+                drive_url: Url::parse(DRIVE_API_URL).expect("invalid DRIVE_API_URL"),
+                fields: None,
+                ranges: Vec::new(),
+                stale_if_error: false,
+            },
         }
     }
 
+    /// Returns the path of the gzip compressed cache file of a spreadsheet.
     pub fn prepare_cache_path(&self, spreadsheet_id: &str) -> Result<PathBuf> {
         fs::create_dir_all(&self.cache_dir)
             .context("Failed to create cache directory")?;
 
-        Ok(self.cache_dir.join(spreadsheet_id))
+        Ok(self.cache_dir.join(format!("{}.json.gz", self.cache_key(spreadsheet_id))))
+    }
+
+    /// Returns the name of the cache files of a spreadsheet.
+    /// Spreadsheets requested with a `fields` mask or ranges are cached separately,
+    /// keyed by a hash of both.
+    fn cache_key(&self, spreadsheet_id: &str) -> String {
+        if self.fields.is_none() && self.ranges.is_empty() {
+            return spreadsheet_id.to_owned();
+        }
+
+        let parts = self.fields.iter().chain(&self.ranges).map(String::as_str);
+
+        format!("{}-{:016x}", spreadsheet_id, fnv1a(parts))
     }
 
     pub async fn get(&self, spreadsheet_id: &str, instrument: &impl Instrument) -> Result<Spreadsheet> {
@@ -37,81 +231,476 @@ impl Client {
         Ok(spreadsheet)
     }
 
+    /// Like `get`, but passes the sheets matched by `selector` to `on_sheet`
+    /// one at a time while parsing, so that only a single sheet has to be kept in memory.
+    pub async fn for_each_sheet(
+        &self,
+        spreadsheet_id: &str,
+        instrument: &impl Instrument,
+        selector: &SheetSelector,
+        on_sheet: impl FnMut(Sheet) -> Result<()>,
+    ) -> Result<()> {
+        let path = self.get_cached_path(spreadsheet_id, instrument)
+            .await
+            .context("Failed to get spreadsheet")?;
+
+        let file = fs::File::open(path)
+            .context("Failed opening cached spreadsheet")?;
+        let reader = BufReader::new(GzDecoder::new(file));
+
+        Spreadsheet::for_each_selected_sheet(reader, selector, on_sheet)
+            .context("Failed to parse spreadsheet")
+    }
+
     pub async fn get_raw(
         &self,
         spreadsheet_id: &str,
         instrument: &impl Instrument,
     ) -> Result<Vec<u8>> {
+        let path = self.get_cached_path(spreadsheet_id, instrument).await?;
+        let file = fs::File::open(path)
+            .context("Failed opening cached spreadsheet")?;
+        let mut data = Vec::new();
+
+        GzDecoder::new(file).read_to_end(&mut data)
+            .context("Failed reading cached spreadsheet")?;
+
+        Ok(data)
+    }
+
+    /// Returns the path of the cached, gzip compressed spreadsheet.
+    /// The spreadsheet is downloaded first if it isn't cached yet
+    /// or the cache is outdated according to the cache policy.
+    pub async fn get_cached_path(
+        &self,
+        spreadsheet_id: &str,
+        instrument: &impl Instrument,
+    ) -> Result<PathBuf> {
+        self.get_cached_path_at(spreadsheet_id, instrument, self.check_revision(spreadsheet_id)).await
+    }
+
+    /// Like `get_cached_path`, but takes the current revision of the spreadsheet from `revision`,
+    /// which is only awaited if the cache policy needs it.
+    async fn get_cached_path_at(
+        &self,
+        spreadsheet_id: &str,
+        instrument: &impl Instrument,
+        revision: impl Future<Output = Result<Revision, String>>,
+    ) -> Result<PathBuf> {
         let path = self.prepare_cache_path(spreadsheet_id)
             .context("Failed to get cache path")?;
+        let metadata_path = self.cache_dir.join(format!("{}.meta.json", self.cache_key(spreadsheet_id)));
+
+        self.migrate_uncompressed_cache(spreadsheet_id, &path, &metadata_path)
+            .context("Failed to compress cached spreadsheet")?;
+
+        let revision = match self.cache_status(&path, &metadata_path, instrument, revision).await? {
+            CacheStatus::Fresh => return Ok(path),
+            CacheStatus::Outdated(revision) => revision,
+        };
+        let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        self.download_to(spreadsheet_id, &path, instrument).await
+            .context("failed downloading spreadsheet")?;
+
+        write_cache_metadata(&metadata_path, &CacheMetadata { fetched_at, revision })?;
+
+        Ok(path)
+    }
+
+    /// Compresses a cache file written by older versions, which stored spreadsheets uncompressed.
+    ///
+    /// Older versions didn't record revisions, so the modification time of the file is taken
+    /// as the time of the download.
+    ///
+    /// Older versions always requested the whole spreadsheet, which contains the fields of any mask.
+    /// Requests for ranges must not return other sheets though, so for them the file is only removed.
+    fn migrate_uncompressed_cache(&self, spreadsheet_id: &str, path: &Path, metadata_path: &Path) -> Result<()> {
+        let legacy_path = self.cache_dir.join(spreadsheet_id);
+
+        if !legacy_path.is_file() {
+            return Ok(());
+        }
+
+        if self.ranges.is_empty() && !path.exists() {
+            let tmp_path = path.with_extension("tmp");
+            let fetched_at = fs::metadata(&legacy_path)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| format!("Failed to get modification time of {}", legacy_path.display()))?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let mut legacy_file = fs::File::open(&legacy_path)
+                .with_context(|| format!("Failed to open {}", legacy_path.display()))?;
+            let file = fs::File::create(&tmp_path)
+                .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+
+            io::copy(&mut legacy_file, &mut encoder)
+                .and_then(|_| encoder.finish())
+                .with_context(|| format!("Failed writing to {}", tmp_path.display()))?;
+
+            fs::rename(&tmp_path, path)
+                .with_context(|| format!("Failed to move {} to {}", tmp_path.display(), path.display()))?;
+
+            write_cache_metadata(metadata_path, &CacheMetadata { fetched_at, revision: Revision::default() })?;
+        }
+
+        match fs::remove_file(&legacy_path) {
+            // Removed by a concurrent download of another sheet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.with_context(|| format!("Failed to remove {}", legacy_path.display())),
+        }
+    }
+
+    /// Decides whether the cached spreadsheet at `path` can be used.
+    ///
+    /// Without a cached spreadsheet, `revision` isn't awaited, so the next check
+    /// compares the modification time of the spreadsheet with the time of the download.
+    /// If the revision can't be checked, this fails unless `stale_if_error` is set,
+    /// in which case the error is reported to `instrument`.
+    async fn cache_status(
+        &self,
+        path: &Path,
+        metadata_path: &Path,
+        instrument: &impl Instrument,
+        revision: impl Future<Output = Result<Revision, String>>,
+    ) -> Result<CacheStatus> {
+        if !path.exists() {
+            return Ok(CacheStatus::Outdated(Revision::default()));
+        }
 
-        let data = if path.exists() {
-            fs::read(path).context("Failed reading cached spreadsheet")?
-        } else {
-            let data = self.get_raw_uncached(spreadsheet_id, instrument).await
-                .context("failed downloading spreadsheet")?;
+        if self.cache_policy == CachePolicy::Refresh {
+            return Ok(CacheStatus::Outdated(revision.await.unwrap_or_default()));
+        }
+
+        let cached = fs::read(metadata_path).ok()
+            .and_then(|metadata| serde_json::from_slice::<CacheMetadata>(&metadata).ok());
 
-            fs::write(path, &data).context("Failed writing spreadsheet to cache")?;
+        if let (Some(cached), CachePolicy::MaxAge(max_age)) = (&cached, self.cache_policy) {
+            if cached.age() < max_age {
+                return Ok(CacheStatus::Fresh);
+            }
+        }
 
-            data
+        let revision = match revision.await {
+            Ok(revision) => revision,
+            Err(err) if self.stale_if_error => {
+                instrument.using_stale_cache(&err);
+                return Ok(CacheStatus::Fresh);
+            },
+            Err(err) => bail!("Failed to check whether the cached spreadsheet changed: {}", err),
         };
 
-        Ok(data)
+        let cached = match cached {
+            Some(cached) => cached,
+            None => return Ok(CacheStatus::Outdated(revision)),
+        };
+
+        if cached.revision.is_known() {
+            if revision.is_known() && cached.revision == revision {
+                return Ok(CacheStatus::Fresh);
+            }
+
+            return Ok(CacheStatus::Outdated(revision));
+        }
+
+        if !revision.modified_before(cached.fetched_at) {
+            return Ok(CacheStatus::Outdated(revision));
+        }
+
+        // Remember the revision, so that later checks don't depend on the clock
+        write_cache_metadata(metadata_path, &CacheMetadata { revision, ..cached })?;
+
+        Ok(CacheStatus::Fresh)
+    }
+
+    /// Like `get_revision`, but returns errors as text that can be shared between downloads.
+    async fn check_revision(&self, spreadsheet_id: &str) -> Result<Revision, String> {
+        self.get_revision(spreadsheet_id).await
+            .map_err(|err| self.auth.redact(&err.root_cause().to_string()))
+    }
+
+    /// Fetches the modification time and version of the spreadsheet from the Drive API,
+    /// which is much cheaper than fetching the spreadsheet itself.
+    async fn get_revision(&self, spreadsheet_id: &str) -> Result<Revision> {
+        let mut url = self.drive_url.join(spreadsheet_id)?;
+
+        url.query_pairs_mut()
+            .append_pair("fields", "modifiedTime,version");
+
+        // The revision check has no progress of its own to report retries to
+        let response = self.retry_policy.run(&NoInstrument, || async {
+            let response = self.auth.send(reqwest::Client::new().get(url.clone()))
+                .await
+                .context("Metadata request failed")?;
+            let response = retry::check_status(response)
+                .context("API returned an error")?
+                .bytes()
+                .await
+                .context("Failed to download spreadsheet metadata")?;
+
+            Ok(response)
+        })
+        .await?;
+        let revision = serde_json::from_slice(&response)
+            .context("Failed to parse spreadsheet metadata")?;
+
+        Ok(revision)
+    }
+
+    /// Fetches the properties of all sheets of the spreadsheet, without any of their data.
+    pub async fn get_sheet_properties(
+        &self,
+        spreadsheet_id: &str,
+        instrument: &impl Instrument,
+    ) -> Result<Vec<SheetProperties>> {
+        #[derive(Deserialize)]
+        struct SheetList {
+            #[serde(default)]
+            sheets: Vec<SheetEntry>,
+        }
+
+        #[derive(Deserialize)]
+        struct SheetEntry {
+            properties: SheetProperties,
+        }
+
+        let mut url = self.sheets_url.join(spreadsheet_id)?;
+
+        url.query_pairs_mut()
+            .append_pair("fields", "sheets(properties(title,sheetId,index,hidden,gridProperties))");
+
+        let response = self.retry_policy.run(instrument, || async {
+            instrument.starting_request();
+
+            let response = self.auth.send(reqwest::Client::new().get(url.clone()))
+                .await
+                .context("API request failed")?;
+            let response = retry::check_status(response)
+                .context("API returned an error")?
+                .bytes()
+                .await
+                .context("Failed to download sheet properties")?;
+
+            Ok(response)
+        })
+        .await?;
+
+        instrument.received_bytes(response.len());
+        instrument.request_finished();
+
+        let sheet_list = serde_json::from_slice::<SheetList>(&response)
+            .context("Failed to parse sheet properties")?;
+
+        Ok(sheet_list.sheets.into_iter().map(|sheet| sheet.properties).collect())
     }
 
-    async fn get_raw_uncached(&self, spreadsheet_id: &str, instrument: &impl Instrument) -> Result<Vec<u8>> {
+    /// Like `for_each_sheet`, but downloads and caches each of `sheets` separately,
+    /// up to `concurrency` at a time.
+    /// `instrument_for` is called for every sheet before the first download starts.
+    ///
+    /// All downloads are finished before failing, so that the sheets which could be downloaded
+    /// are cached and don't have to be downloaded again.
+    /// The sheets are passed to `on_sheet` in the order of `sheets`.
+    pub async fn for_each_sheet_concurrently<I: Instrument>(
+        &self,
+        spreadsheet_id: &str,
+        sheets: &[SheetProperties],
+        concurrency: usize,
+        instrument_for: impl Fn(&SheetProperties) -> I,
+        mut on_sheet: impl FnMut(Sheet) -> Result<()>,
+    ) -> Result<()> {
+        let revision = self.check_revision(spreadsheet_id).shared();
+        let instruments = sheets.iter().map(instrument_for).collect::<Vec<_>>();
+
+        let mut downloads = stream::iter(sheets.iter().zip(&instruments).enumerate())
+            .map(|(i, (properties, instrument))| {
+                let client = Client {
+                    ranges: vec![sheet_range(properties.title())],
+                    ..self.clone()
+                };
+                let revision = revision.clone();
+
+                async move {
+                    let path = client.get_cached_path_at(spreadsheet_id, instrument, revision).await
+                        .with_context(|| format!("Failed to get sheet '{}'", properties.title()));
+
+                    (i, path)
+                }
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await;
+
+        downloads.sort_by_key(|&(i, _)| i);
+
+        let mut paths = Vec::new();
+        let mut failed = Vec::new();
+        let mut first_error = None;
+
+        for (i, path) in downloads {
+            match path {
+                Ok(path) => paths.push(path),
+                Err(error) => {
+                    failed.push(format!("'{}'", sheets[i].title()));
+                    first_error.get_or_insert(error);
+                },
+            }
+        }
+
+        if let Some(error) = first_error {
+            return Err(error.context(format!(
+                "Failed to download {} of {} sheets: {}",
+                failed.len(),
+                sheets.len(),
+                failed.join(", "),
+            )));
+        }
+
+        for path in paths {
+            let file = fs::File::open(path)
+                .context("Failed opening cached sheet")?;
+            let reader = BufReader::new(GzDecoder::new(file));
+
+            Spreadsheet::for_each_sheet(reader, &mut on_sheet)
+                .context("Failed to parse sheet")?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the spreadsheet to `path` and compresses it, without buffering it in memory.
+    async fn download_to(&self, spreadsheet_id: &str, path: &Path, instrument: &impl Instrument) -> Result<()> {
         let client = reqwest::Client::builder()
             .gzip(true)
             .brotli(true)
             .build()?;
-        let mut url = Url::parse("https://sheets.googleapis.com/v4/spreadsheets/")?
-            .join(spreadsheet_id)?;
+        let mut url = self.sheets_url.join(spreadsheet_id)?;
 
         url.query_pairs_mut()
-            .append_pair("includeGridData", "true")
-            .append_pair("key", &self.api_key);
+            .append_pair("includeGridData", "true");
 
+        if let Some(fields) = &self.fields {
+            url.query_pairs_mut().append_pair("fields", fields);
+        }
+
+        for range in &self.ranges {
+            url.query_pairs_mut().append_pair("ranges", range);
+        }
+
+        // Download to a temporary file first, so that failed downloads don't end up in the cache
+        let tmp_path = path.with_extension("tmp");
+
+        let result = self.retry_policy.run(instrument, || self.download_attempt(&client, &url, &tmp_path, instrument)).await;
+
+        if result.is_err() {
+            // Partially written, or created by an earlier attempt
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+
+        result?;
+
+        instrument.request_finished();
+
+        tokio::fs::rename(&tmp_path, path).await
+            .with_context(|| format!("Failed to move {} to {}", tmp_path.display(), path.display()))?;
+
+        Ok(())
+    }
+
+    async fn download_attempt(
+        &self,
+        client: &reqwest::Client,
+        url: &Url,
+        tmp_path: &Path,
+        instrument: &impl Instrument,
+    ) -> Result<()> {
         instrument.starting_request();
 
-        let mut response: reqwest::Response = client.get(url)
-            .header("Accept", "application/json")
-            .send()
+        let request = client.get(url.clone())
+            .header("Accept", "application/json");
+        let response = self.auth.send(request)
             .await
-            .context("API request failed")?
-            .error_for_status()
+            .context("API request failed")?;
+        let mut response = retry::check_status(response)
             .context("API returned an error")?;
 
-        let mut data = Vec::with_capacity(DOWNLOAD_BUF_CAPACITY); // 1 GB;
+        let mut file = tokio::fs::File::create(&tmp_path).await
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        // Compresses into memory, the compressed output is moved to the file after every chunk
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
 
         while let Some(chunk) = response.chunk().await.context("chunk failed")? {
             instrument.received_bytes(chunk.len());
-            data.extend_from_slice(&chunk);
+            encoder.write_all(&chunk)?;
+            let compressed = std::mem::take(encoder.get_mut());
+            file.write_all(&compressed).await
+                .with_context(|| format!("Failed writing to {}", tmp_path.display()))?;
         }
 
-        instrument.request_finished();
-        data.shrink_to_fit();
+        let compressed = encoder.finish()?;
+        file.write_all(&compressed).await
+            .with_context(|| format!("Failed writing to {}", tmp_path.display()))?;
+        file.flush().await
+            .with_context(|| format!("Failed writing to {}", tmp_path.display()))?;
 
-        Ok(data)
+        Ok(())
     }
 }
 
-pub struct FnInstrument<T, F1, F2, F3>
+/// 64 bit FNV-1a hash of `parts`, which unlike `DefaultHasher` is stable across releases.
+fn fnv1a<'a>(parts: impl Iterator<Item = &'a str>) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+
+    for part in parts {
+        // Terminate every part, so that moving characters between parts changes the hash
+        for &byte in part.as_bytes().iter().chain(&[0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    hash
+}
+
+/// Writes the metadata of a cached spreadsheet.
+fn write_cache_metadata(path: &Path, metadata: &CacheMetadata) -> Result<()> {
+    let metadata = serde_json::to_vec_pretty(metadata)?;
+
+    fs::write(path, metadata)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+enum CacheStatus {
+    Fresh,
+    /// The cache is missing or outdated, the spreadsheet has to be downloaded
+    Outdated(Revision),
+}
+
+pub struct FnInstrument<T, F1, F2, F3, F4, F5>
 where
     F1: Fn(&T),
     F2: Fn(&T, usize),
     F3: Fn(&T),
+    F4: Fn(&T, u32, Duration, &str),
+    F5: Fn(&T, &str),
 {
     pub this: T,
     pub starting_request: F1,
     pub received_bytes: F2,
     pub request_finished: F3,
+    pub retrying: F4,
+    pub using_stale_cache: F5,
 }
 
-impl<T, F1, F2, F3> Instrument for FnInstrument<T, F1, F2, F3>
+impl<T, F1, F2, F3, F4, F5> Instrument for FnInstrument<T, F1, F2, F3, F4, F5>
 where
     F1: Fn(&T),
     F2: Fn(&T, usize),
     F3: Fn(&T),
+    F4: Fn(&T, u32, Duration, &str),
+    F5: Fn(&T, &str),
 {
     fn starting_request(&self) {
         (self.starting_request)(&self.this)
@@ -124,10 +713,51 @@ where
     fn request_finished(&self) {
         (self.request_finished)(&self.this)
     }
+
+    fn retrying(&self, retry: u32, delay: Duration, reason: &str) {
+        (self.retrying)(&self.this, retry, delay, reason)
+    }
+
+    fn using_stale_cache(&self, reason: &str) {
+        (self.using_stale_cache)(&self.this, reason)
+    }
+}
+
+/// Reports nothing.
+struct NoInstrument;
+
+impl Instrument for NoInstrument {
+    fn starting_request(&self) {}
+    fn received_bytes(&self, _amount: usize) {}
+    fn request_finished(&self) {}
 }
 
 pub trait Instrument {
     fn starting_request(&self);
     fn received_bytes(&self, amount: usize);
     fn request_finished(&self);
+    /// Called before waiting `delay` for retry number `retry` of a failed request
+    fn retrying(&self, _retry: u32, _delay: Duration, _reason: &str) {}
+    /// Called when the cached spreadsheet is used, because checking whether it changed failed
+    /// with `reason` and stale caches are allowed on errors
+    fn using_stale_cache(&self, _reason: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_drive_timestamps() {
+        assert_eq!(unix_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(unix_time("2020-06-01T09:30:15.123Z"), Some(1_591_003_815));
+        assert_eq!(unix_time("2000-02-29T23:59:59Z"), Some(951_868_799));
+    }
+
+    #[test]
+    fn rejects_other_timestamps() {
+        for timestamp in &["", "2020-06-01", "2020-06-01T09:30:15", "2020-06-01T09:30:15+02:00", "2020-13-01T00:00:00Z", "1969-12-31T23:59:59Z"] {
+            assert_eq!(unix_time(timestamp), None, "{}", timestamp);
+        }
+    }
 }
@@ -0,0 +1,254 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use anyhow::{Context, Result};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs1v15::SigningKey,
+    pkcs8::DecodePrivateKey,
+    signature::{SignatureEncoding, Signer},
+    RsaPrivateKey,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use super::retry;
+
+pub const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+/// Read access to spreadsheets and the metadata used for checking whether they changed
+pub const SCOPES: &str = "https://www.googleapis.com/auth/spreadsheets.readonly https://www.googleapis.com/auth/drive.metadata.readonly";
+
+/// Tokens are refreshed when they expire within this time,
+/// so that they don't expire while a request is in flight.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// How requests to the Google APIs are authenticated.
+pub enum Auth {
+    /// An API key, which can only read public spreadsheets
+    ApiKey(String),
+    /// A service account, which can read the spreadsheets shared with it
+    ServiceAccount(Box<ServiceAccount>),
+}
+
+impl Auth {
+    /// Adds credentials to `request`.
+    pub async fn authorize(&self, request: RequestBuilder) -> Result<RequestBuilder> {
+        match self {
+            Auth::ApiKey(api_key) => Ok(request.query(&[("key", api_key)])),
+            Auth::ServiceAccount(service_account) => {
+                let token = service_account.access_token().await
+                    .context("Failed to get access token for service account")?;
+
+                Ok(request.bearer_auth(token))
+            },
+        }
+    }
+
+    /// Authorizes and sends `request`.
+    ///
+    /// If the access token of a service account is rejected with `401 Unauthorized`,
+    /// e.g. because it has been revoked, it is dropped and the request is sent once more
+    /// with a new token.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let retry = request.try_clone();
+        let response = self.authorize(request).await?
+            .send()
+            .await?;
+
+        match (self, retry) {
+            (Auth::ServiceAccount(service_account), Some(retry)) if response.status() == StatusCode::UNAUTHORIZED => {
+                service_account.clear_access_token().await;
+
+                Ok(self.authorize(retry).await?.send().await?)
+            },
+            _ => Ok(response),
+        }
+    }
+
+    /// Replaces secrets that may end up in error messages, i.e. the api key in urls.
+    pub fn redact(&self, text: &str) -> String {
+        match self {
+            Auth::ApiKey(api_key) if !api_key.is_empty() => text.replace(api_key.as_str(), "<REDACTED>"),
+            _ => text.to_owned(),
+        }
+    }
+}
+
+impl From<String> for Auth {
+    fn from(api_key: String) -> Self {
+        Auth::ApiKey(api_key)
+    }
+}
+
+impl From<&str> for Auth {
+    fn from(api_key: &str) -> Self {
+        Auth::ApiKey(api_key.to_owned())
+    }
+}
+
+impl From<ServiceAccount> for Auth {
+    fn from(service_account: ServiceAccount) -> Self {
+        Auth::ServiceAccount(Box::new(service_account))
+    }
+}
+
+/// A service account, authenticated with OAuth access tokens
+/// obtained by exchanging a JWT signed with the account's private key.
+pub struct ServiceAccount {
+    key: ServiceAccountKey,
+    /// The parsed private key of `key`
+    signing_key: SigningKey<Sha256>,
+    token_url: Url,
+    scopes: String,
+    /// The last access token, shared by all requests until it expires
+    token: Mutex<Option<AccessToken>>,
+}
+
+/// The parts of a service account json key file that are needed for authentication.
+#[derive(Deserialize)]
+pub struct ServiceAccountKey {
+    pub client_email: String,
+    /// RSA private key in PEM format, PKCS#8 or PKCS#1
+    pub private_key: String,
+    pub private_key_id: Option<String>,
+    pub token_uri: Option<String>,
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl ServiceAccount {
+    /// Uses the token endpoint given by the key, or `TOKEN_URL` if there is none.
+    pub fn new(key: ServiceAccountKey) -> Result<Self> {
+        let token_url = key.token_uri.as_deref().unwrap_or(TOKEN_URL);
+        let token_url = Url::parse(token_url)
+            .with_context(|| format!("Invalid token uri '{}'", token_url))?;
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&key.private_key)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&key.private_key))
+            .context("Invalid private key")?;
+
+        Ok(Self {
+            key,
+            signing_key: SigningKey::new(private_key),
+            token_url,
+            scopes: SCOPES.into(),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Loads a json key file, as downloaded from the Google Cloud console.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let key = serde_json::from_slice(&json)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        Self::new(key)
+    }
+
+    pub fn with_token_url(mut self, token_url: Url) -> Self {
+        self.token_url = token_url;
+        self
+    }
+
+    /// Space separated OAuth scopes, `SCOPES` by default
+    pub fn with_scopes(mut self, scopes: impl Into<String>) -> Self {
+        self.scopes = scopes.into();
+        self
+    }
+
+    /// Returns the cached access token, or requests a new one if it is about to expire.
+    pub async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+
+        match &*token {
+            Some(token) if token.expires_at > Instant::now() + TOKEN_EXPIRY_MARGIN => {
+                return Ok(token.token.clone());
+            },
+            _ => {},
+        }
+
+        let requested_at = Instant::now();
+        let response = self.request_token().await?;
+        let access_token = response.access_token.clone();
+
+        *token = Some(AccessToken {
+            token: response.access_token,
+            expires_at: requested_at + Duration::from_secs(response.expires_in),
+        });
+
+        Ok(access_token)
+    }
+
+    /// Drops the cached access token, so that the next request gets a new one.
+    pub async fn clear_access_token(&self) {
+        *self.token.lock().await = None;
+    }
+
+    async fn request_token(&self) -> Result<TokenResponse> {
+        let assertion = self.signed_jwt()
+            .context("Failed to sign token request")?;
+
+        let response = reqwest::Client::new()
+            .post(self.token_url.clone())
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .context("Token request failed")?;
+        let response = retry::check_status(response)
+            .context("Token endpoint returned an error")?
+            .bytes()
+            .await
+            .context("Failed to download access token")?;
+        let response = serde_json::from_slice(&response)
+            .context("Failed to parse access token")?;
+
+        Ok(response)
+    }
+
+    /// Returns a JWT asserting the identity of the service account, signed with RS256.
+    fn signed_jwt(&self) -> Result<String> {
+        let issued_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut header = json!({
+            "alg": "RS256",
+            "typ": "JWT",
+        });
+
+        if let Some(key_id) = &self.key.private_key_id {
+            header["kid"] = key_id.as_str().into();
+        }
+
+        let claims = json!({
+            "iss": self.key.client_email,
+            "scope": self.scopes,
+            "aud": self.token_url.as_str(),
+            "iat": issued_at,
+            "exp": issued_at + 3600,
+        });
+
+        let message = format!("{}.{}", base64_url(&serde_json::to_vec(&header)?), base64_url(&serde_json::to_vec(&claims)?));
+
+        let signature = self.signing_key.try_sign(message.as_bytes())?;
+
+        Ok(format!("{}.{}", message, base64_url(&signature.to_bytes())))
+    }
+}
+
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::hash_map::RandomState,
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    time::{Duration, SystemTime},
+};
+use anyhow::{Error, Result};
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use super::Instrument;
+
+/// How often and how long to wait before retrying failed requests.
+///
+/// Requests are retried on connection errors, `429 Too Many Requests` and `5xx` responses.
+/// The delay doubles with every retry up to `max_delay` and is randomized,
+/// unless the server asks for a specific delay with a `Retry-After` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn never() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Runs `attempt` until it succeeds, fails permanently or the retries are used up.
+    /// Every retry is reported to `instrument`.
+    pub async fn run<T, F, Fut>(&self, instrument: &impl Instrument, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+
+        loop {
+            let error = match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+
+            let failure = match TemporaryFailure::of(&error) {
+                Some(failure) if retry < self.max_retries => failure,
+                _ => return Err(error),
+            };
+
+            retry += 1;
+
+            let delay = failure.retry_after.unwrap_or_else(|| self.backoff(retry));
+            instrument.retrying(retry, delay, &failure.reason);
+
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    /// Returns a random delay between half and all of `initial_delay * 2^(retry - 1)`,
+    /// but no more than `max_delay`.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.initial_delay
+            .checked_mul(1 << retry.saturating_sub(1).min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        delay / 2 + delay.mul_f64(random_fraction() / 2.)
+    }
+}
+
+/// Returns a random number between 0 and 1.
+fn random_fraction() -> f64 {
+    // Every `RandomState` gets new random keys, which is good enough for jitter
+    let random = RandomState::new().build_hasher().finish();
+
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A response with an unsuccessful status code.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    /// The delay requested by a `Retry-After` header
+    pub retry_after: Option<Duration>,
+}
+
+impl StatusError {
+    pub fn is_temporary(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS || self.status.is_server_error()
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server responded with {}", self.status)
+    }
+}
+
+impl StdError for StatusError {}
+
+/// Turns responses with an unsuccessful status code into a `StatusError`.
+pub fn check_status(response: Response) -> Result<Response, StatusError> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = response.headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()));
+
+    Err(StatusError { status, retry_after })
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds
+/// or an HTTP date like `Wed, 21 Oct 2015 07:28:00 GMT`.
+/// Dates in the past mean retrying right away.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+struct TemporaryFailure {
+    reason: String,
+    retry_after: Option<Duration>,
+}
+
+impl TemporaryFailure {
+    /// Returns the temporary failure that caused `error`, if there is one.
+    fn of(error: &Error) -> Option<Self> {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<StatusError>() {
+                return Some(Self {
+                    reason: error.to_string(),
+                    retry_after: error.retry_after,
+                })
+                .filter(|_| error.is_temporary());
+            }
+
+            if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+                if error.is_builder() || error.is_redirect() || error.is_status() {
+                    return None;
+                }
+
+                // The root cause of transport errors doesn't contain the url, which might contain the api key
+                return Some(Self {
+                    reason: cause_of(error),
+                    retry_after: None,
+                });
+            }
+
+            if let Some(error) = cause.downcast_ref::<io::Error>() {
+                return match error.kind() {
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof => Some(Self {
+                        reason: error.to_string(),
+                        retry_after: None,
+                    }),
+                    _ => None,
+                };
+            }
+        }
+
+        None
+    }
+}
+
+fn cause_of(error: &reqwest::Error) -> String {
+    let mut cause: &dyn StdError = error;
+
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+
+    if cause.is::<reqwest::Error>() {
+        return "Request failed".into();
+    }
+
+    cause.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("120", UNIX_EPOCH), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", UNIX_EPOCH), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn parses_retry_after_dates() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_445_412_430);
+
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now), Some(Duration::from_secs(50)));
+        // Obsolete formats are allowed as well
+        assert_eq!(parse_retry_after("Wednesday, 21-Oct-15 07:28:00 GMT", now), Some(Duration::from_secs(50)));
+        assert_eq!(parse_retry_after("Wed Oct 21 07:28:00 2015", now), Some(Duration::from_secs(50)));
+    }
+
+    #[test]
+    fn retry_after_past_dates_mean_now() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_445_412_530);
+
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn ignores_invalid_retry_after() {
+        for value in &["", "soon", "-1", "1.5", "Wed, 32 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(parse_retry_after(value, UNIX_EPOCH), None, "{}", value);
+        }
+    }
+}
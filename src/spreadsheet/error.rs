@@ -0,0 +1,98 @@
+use std::{error::Error, fmt};
+
+/// An error that occurred while decoding the value of a single cell.
+#[derive(Debug, Clone)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    /// The raw value of the cell that could not be decoded
+    pub raw_value: String,
+}
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind, raw_value: impl fmt::Display) -> Self {
+        Self {
+            kind,
+            raw_value: raw_value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.raw_value)
+    }
+}
+
+impl Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// The effective value has a type that can't be exported
+    UnsupportedValue,
+    /// The cell contains a formula that can't be evaluated
+    UnsupportedFormula,
+    /// The cell evaluated to a spreadsheet error like `#N/A`
+    ErrorValue,
+    /// The value is not allowed by the column's dropdown
+    NotAllowed,
+    /// A field derived from the cell, like `<column>_url`, has the key of another column
+    KeyCollision,
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeErrorKind::UnsupportedValue => write!(f, "Unsupported value"),
+            DecodeErrorKind::UnsupportedFormula => write!(f, "Unsupported formula"),
+            DecodeErrorKind::ErrorValue => write!(f, "Error value"),
+            DecodeErrorKind::NotAllowed => write!(f, "Value not allowed by dropdown"),
+            DecodeErrorKind::KeyCollision => write!(f, "Skipped field with the key of another column"),
+        }
+    }
+}
+
+/// A `DecodeError` together with the location of the cell it occurred in.
+#[derive(Debug, Clone)]
+pub struct CellError {
+    /// Title of the sheet containing the cell
+    pub sheet: String,
+    /// Zero based index of the row within the sheet
+    pub row: usize,
+    /// Normalized column title, or the column letter if the title is unknown
+    pub column: String,
+    pub error: DecodeError,
+}
+
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Sheet '{}', row index {}, column '{}': {}",
+            self.sheet,
+            self.row,
+            self.column,
+            self.error,
+        )
+    }
+}
+
+impl Error for CellError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Returns the A1 notation letter(s) of the column with the given zero based index.
+pub fn column_letter(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut n = index + 1;
+
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        letters.push(b'A' + rem as u8);
+        n = (n - 1) / 26;
+    }
+
+    letters.reverse();
+    String::from_utf8(letters).unwrap()
+}
@@ -0,0 +1,138 @@
+use std::{fmt, str::FromStr};
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+use super::TextFormat;
+
+/// The effective format of a cell, as far as the exporter cares about it.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all="camelCase")]
+pub struct CellFormat {
+    pub background_color: Option<Color>,
+    #[serde(default)]
+    pub text_format: TextFormat,
+}
+
+/// A color with components between 0 and 1. Missing components are 0.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Color {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+}
+
+impl Color {
+    pub fn to_rgb(self) -> [u8; 3] {
+        let component = |c: f32| (c.clamp(0., 1.) * 255.).round() as u8;
+
+        [component(self.red), component(self.green), component(self.blue)]
+    }
+}
+
+impl fmt::Display for Color {
+    /// Formats the color as `#rrggbb`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.to_rgb();
+        write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+/// A format that marks cells with a named flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatFlag {
+    pub condition: FormatCondition,
+    pub flag: String,
+}
+
+impl FormatFlag {
+    pub fn new(condition: FormatCondition, flag: impl Into<String>) -> Self {
+        Self {
+            condition,
+            flag: flag.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatCondition {
+    /// The background has exactly this color
+    Background([u8; 3]),
+    Bold,
+    Strikethrough,
+}
+
+impl FormatCondition {
+    pub fn matches(self, format: &CellFormat) -> bool {
+        match self {
+            FormatCondition::Background(rgb) => format.background_color
+                .map(|color| color.to_rgb() == rgb)
+                .unwrap_or(false),
+            FormatCondition::Bold => format.text_format.bold.unwrap_or(false),
+            FormatCondition::Strikethrough => format.text_format.strikethrough.unwrap_or(false),
+        }
+    }
+}
+
+impl FromStr for FormatCondition {
+    type Err = Error;
+
+    /// Parses `#rrggbb`, `bold` or `strikethrough`
+    fn from_str(condition: &str) -> Result<Self> {
+        match condition {
+            "bold" => return Ok(FormatCondition::Bold),
+            "strikethrough" => return Ok(FormatCondition::Strikethrough),
+            _ => {},
+        }
+
+        let hex = condition.strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.is_ascii())
+            .with_context(|| format!("Unknown format condition '{}', expected '#rrggbb', 'bold' or 'strikethrough'", condition))?;
+
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16)
+            .with_context(|| format!("Invalid color '{}'", condition));
+
+        Ok(FormatCondition::Background([component(0)?, component(2)?, component(4)?]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_format_conditions() {
+        assert_eq!("bold".parse::<FormatCondition>().unwrap(), FormatCondition::Bold);
+        assert_eq!("strikethrough".parse::<FormatCondition>().unwrap(), FormatCondition::Strikethrough);
+        assert_eq!("#ff8000".parse::<FormatCondition>().unwrap(), FormatCondition::Background([255, 128, 0]));
+        assert_eq!("#FF8000".parse::<FormatCondition>().unwrap(), FormatCondition::Background([255, 128, 0]));
+    }
+
+    #[test]
+    fn rejects_invalid_format_conditions() {
+        for condition in &["", "italic", "ff8000", "#ff80", "#ff80000", "#gg8000", "#ff800é"] {
+            assert!(condition.parse::<FormatCondition>().is_err(), "{}", condition);
+        }
+    }
+
+    #[test]
+    fn formats_colors_as_hex() {
+        let color = Color { red: 1., green: 0.5, blue: 0. };
+
+        assert_eq!(color.to_rgb(), [255, 128, 0]);
+        assert_eq!(color.to_string(), "#ff8000");
+        assert_eq!(Color { red: 2., green: -1., blue: 0. }.to_string(), "#ff0000");
+    }
+
+    #[test]
+    fn matches_background_by_rgb() {
+        let format = CellFormat {
+            background_color: Some(Color { red: 0.999, green: 0., blue: 0. }),
+            ..CellFormat::default()
+        };
+
+        assert!(FormatCondition::Background([255, 0, 0]).matches(&format));
+        assert!(!FormatCondition::Background([254, 0, 0]).matches(&format));
+        assert!(!FormatCondition::Bold.matches(&format));
+        assert!(!FormatCondition::Background([0, 0, 0]).matches(&CellFormat::default()));
+    }
+}
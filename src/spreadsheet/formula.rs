@@ -0,0 +1,416 @@
+//! A small parser for the few formulas the exporter understands:
+//! - `IMAGE(url, [mode], [height], [width])`
+//! - `HYPERLINK(url, [label])`
+//!
+//! Only literal arguments are supported. Calls with other arguments, like cell references,
+//! are treated like calls of any other function.
+
+use std::{borrow::Cow, error::Error, fmt};
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Formula<'a> {
+    Image(ImageFormula<'a>),
+    Hyperlink {
+        url: Cow<'a, str>,
+        label: Option<Cow<'a, str>>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ImageFormula<'a> {
+    pub url: Cow<'a, str>,
+    /// 1 = fit, 2 = stretch, 3 = original size, 4 = custom size
+    pub mode: u8,
+    pub height: Option<f64>,
+    pub width: Option<f64>,
+}
+
+impl ImageFormula<'_> {
+    /// Returns the size and mode arguments as json, without the url.
+    pub fn metadata(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mode": self.mode,
+            "height": self.height,
+            "width": self.width,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Arg<'a> {
+    String(Cow<'a, str>),
+    Number(f64),
+}
+
+/// Parses a formula.
+///
+/// Returns `Ok(None)` for formulas calling any other function
+/// and for calls whose arguments are not all literals, e.g. `IMAGE(A2)`.
+/// Returns an error if `IMAGE` or `HYPERLINK` are called with invalid arguments.
+pub fn parse(formula: &str) -> Result<Option<Formula<'_>>, ParseError> {
+    let mut parser = Parser { rest: formula.trim() };
+
+    parser.eat('=');
+    let function = parser.identifier();
+    let function = function.to_ascii_uppercase();
+
+    if function != "IMAGE" && function != "HYPERLINK" {
+        return Ok(None);
+    }
+
+    let args = match parser.args()? {
+        Some(args) => args,
+        None => return Ok(None),
+    };
+
+    // The result is used in a larger expression, e.g. `IMAGE(...) & ""`
+    if !parser.rest.is_empty() {
+        return Ok(None);
+    }
+
+    let formula = match function.as_str() {
+        "IMAGE" => Formula::Image(image(args)?),
+        _ => hyperlink(args)?,
+    };
+
+    Ok(Some(formula))
+}
+
+fn image(args: Vec<Arg<'_>>) -> Result<ImageFormula<'_>, ParseError> {
+    let mut args = args.into_iter();
+    let url = string_arg(args.next(), "url")?
+        .ok_or_else(|| ParseError::new("IMAGE() without url"))?;
+    let mode = number_arg(args.next(), "mode")?.unwrap_or(1.);
+    let height = number_arg(args.next(), "height")?;
+    let width = number_arg(args.next(), "width")?;
+
+    if args.next().is_some() {
+        return Err(ParseError::new("Too many arguments for IMAGE()"));
+    }
+
+    if !(1. ..=4.).contains(&mode) || mode.fract() != 0. {
+        return Err(ParseError::new(format!("Invalid IMAGE() mode: {}", mode)));
+    }
+
+    Ok(ImageFormula {
+        url,
+        mode: mode as u8,
+        height,
+        width,
+    })
+}
+
+fn hyperlink(args: Vec<Arg<'_>>) -> Result<Formula<'_>, ParseError> {
+    let mut args = args.into_iter();
+    let url = string_arg(args.next(), "url")?
+        .ok_or_else(|| ParseError::new("HYPERLINK() without url"))?;
+    let label = string_arg(args.next(), "label")?;
+
+    if args.next().is_some() {
+        return Err(ParseError::new("Too many arguments for HYPERLINK()"));
+    }
+
+    Ok(Formula::Hyperlink {
+        url,
+        label,
+    })
+}
+
+fn string_arg<'a>(arg: Option<Arg<'a>>, name: &str) -> Result<Option<Cow<'a, str>>, ParseError> {
+    match arg {
+        Some(Arg::String(value)) => Ok(Some(value)),
+        Some(Arg::Number(_)) => Err(ParseError::new(format!("Expected a string for {}", name))),
+        None => Ok(None),
+    }
+}
+
+fn number_arg(arg: Option<Arg<'_>>, name: &str) -> Result<Option<f64>, ParseError> {
+    match arg {
+        Some(Arg::Number(value)) => Ok(Some(value)),
+        Some(Arg::String(_)) => Err(ParseError::new(format!("Expected a number for {}", name))),
+        None => Ok(None),
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+
+        if self.rest.starts_with(c) {
+            self.rest = &self.rest[c.len_utf8()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    fn identifier(&mut self) -> &'a str {
+        self.skip_whitespace();
+
+        let end = self.rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+            .unwrap_or(self.rest.len());
+        let (identifier, rest) = self.rest.split_at(end);
+        self.rest = rest;
+
+        identifier
+    }
+
+    /// Parses a parenthesized list of arguments.
+    /// Both `,` and `;` (used by some locales) are accepted as separators.
+    ///
+    /// Returns `None` if an argument is not a literal.
+    fn args(&mut self) -> Result<Option<Vec<Arg<'a>>>, ParseError> {
+        if !self.eat('(') {
+            return Err(ParseError::new("Expected '('"));
+        }
+
+        let mut args = Vec::new();
+
+        if self.eat(')') {
+            return Ok(Some(args));
+        }
+
+        loop {
+            match self.arg()? {
+                Some(arg) => args.push(arg),
+                None => return Ok(None),
+            }
+
+            if self.eat(',') || self.eat(';') {
+                continue;
+            }
+
+            if self.eat(')') {
+                break;
+            }
+
+            if self.rest.is_empty() {
+                return Err(ParseError::new("Expected ')'"));
+            }
+
+            // The literal is part of a larger expression, e.g. `"https://" & A2`
+            return Ok(None);
+        }
+
+        self.skip_whitespace();
+
+        Ok(Some(args))
+    }
+
+    /// Parses a string or number literal.
+    /// Returns `None` for any other argument, like a cell reference or function call.
+    fn arg(&mut self) -> Result<Option<Arg<'a>>, ParseError> {
+        self.skip_whitespace();
+
+        let arg = match self.rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => Arg::String(self.string(quote)?),
+            Some(c) if c.is_ascii_digit() || c == '.' || c == '-' => match self.number() {
+                Some(number) => Arg::Number(number),
+                None => return Ok(None),
+            },
+            Some(_) => return Ok(None),
+            None => return Err(ParseError::new("Expected an argument")),
+        };
+
+        Ok(Some(arg))
+    }
+
+    /// Parses a quoted string. Quotes within the string are escaped by doubling them.
+    fn string(&mut self, quote: char) -> Result<Cow<'a, str>, ParseError> {
+        let input = &self.rest[1..];
+        let mut value = Cow::Borrowed("");
+        let mut start = 0;
+        let mut chars = input.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            if c != quote {
+                continue;
+            }
+
+            if let Some(&(_, next)) = chars.peek() {
+                if next == quote {
+                    value.to_mut().push_str(&input[start..=i]);
+                    chars.next();
+                    start = i + 2;
+                    continue;
+                }
+            }
+
+            match &mut value {
+                Cow::Borrowed(_) => value = Cow::Borrowed(&input[start..i]),
+                Cow::Owned(value) => value.push_str(&input[start..i]),
+            }
+
+            self.rest = &input[i + 1..];
+
+            return Ok(value);
+        }
+
+        Err(ParseError::new("Unterminated string"))
+    }
+
+    /// Parses a number with an optional leading `-`.
+    /// Returns `None` if there is no valid number, e.g. for `-A1`.
+    ///
+    /// Anything following the number, like in `64-1`, is left to the caller.
+    fn number(&mut self) -> Option<f64> {
+        let sign = if self.rest.starts_with('-') { 1 } else { 0 };
+        let end = self.rest[sign..]
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or(self.rest.len(), |end| sign + end);
+        let (number, rest) = self.rest.split_at(end);
+        let number = number.parse().ok()?;
+        self.rest = rest;
+
+        Some(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(url: &str, mode: u8, height: Option<f64>, width: Option<f64>) -> Option<Formula<'_>> {
+        Some(Formula::Image(ImageFormula {
+            url: url.into(),
+            mode,
+            height,
+            width,
+        }))
+    }
+
+    fn hyperlink<'a>(url: &'a str, label: Option<&'a str>) -> Option<Formula<'a>> {
+        Some(Formula::Hyperlink {
+            url: url.into(),
+            label: label.map(Into::into),
+        })
+    }
+
+    #[test]
+    fn image_with_url_only() {
+        assert_eq!(parse(r#"=IMAGE("https://example.com/a.png")"#), Ok(image("https://example.com/a.png", 1, None, None)));
+    }
+
+    #[test]
+    fn image_modes_and_size() {
+        assert_eq!(parse(r#"=IMAGE("a.png", 2)"#), Ok(image("a.png", 2, None, None)));
+        assert_eq!(parse(r#"=IMAGE("a.png", 3)"#), Ok(image("a.png", 3, None, None)));
+        assert_eq!(parse(r#"=IMAGE("a.png", 4, 64, 32.5)"#), Ok(image("a.png", 4, Some(64.), Some(32.5))));
+    }
+
+    #[test]
+    fn function_names_are_case_insensitive_and_whitespace_is_ignored() {
+        assert_eq!(parse(r#"  = image ( "a.png" , 4 ,64, 64 )  "#), Ok(image("a.png", 4, Some(64.), Some(64.))));
+    }
+
+    #[test]
+    fn semicolon_separators() {
+        assert_eq!(parse(r#"=IMAGE("a.png"; 4; 64; 64)"#), Ok(image("a.png", 4, Some(64.), Some(64.))));
+        assert_eq!(parse(r#"=HYPERLINK("https://example.com"; "Example")"#), Ok(hyperlink("https://example.com", Some("Example"))));
+    }
+
+    #[test]
+    fn hyperlink_with_and_without_label() {
+        assert_eq!(parse(r#"=HYPERLINK("https://example.com", "Example")"#), Ok(hyperlink("https://example.com", Some("Example"))));
+        assert_eq!(parse(r#"=HYPERLINK("https://example.com")"#), Ok(hyperlink("https://example.com", None)));
+    }
+
+    #[test]
+    fn doubled_quotes_are_unescaped() {
+        assert_eq!(
+            parse(r#"=HYPERLINK("https://example.com/?q=""fish""", "Say ""hi""")"#),
+            Ok(hyperlink(r#"https://example.com/?q="fish""#, Some(r#"Say "hi""#))),
+        );
+        assert_eq!(parse(r#"=HYPERLINK("""")"#), Ok(hyperlink(r#"""#, None)));
+    }
+
+    #[test]
+    fn single_quoted_strings() {
+        assert_eq!(parse("=IMAGE('https://example.com/it''s.png')"), Ok(image("https://example.com/it's.png", 1, None, None)));
+        assert_eq!(parse(r#"=IMAGE('say "hi".png')"#), Ok(image(r#"say "hi".png"#, 1, None, None)));
+    }
+
+    #[test]
+    fn strings_without_escapes_are_borrowed() {
+        match parse(r#"=HYPERLINK("https://example.com/魚", "魚")"#) {
+            Ok(Some(Formula::Hyperlink { url: Cow::Borrowed(url), label: Some(Cow::Borrowed(label)) })) => {
+                assert_eq!((url, label), ("https://example.com/魚", "魚"));
+            },
+            formula => panic!("Unexpected formula: {:?}", formula),
+        }
+    }
+
+    #[test]
+    fn other_functions_are_ignored() {
+        assert_eq!(parse("=SUM(1, 2)"), Ok(None));
+        assert_eq!(parse(r#"=IFERROR(IMAGE("a.png"), "")"#), Ok(None));
+        assert_eq!(parse("=A1"), Ok(None));
+        assert_eq!(parse(""), Ok(None));
+    }
+
+    #[test]
+    fn non_literal_arguments_are_ignored() {
+        assert_eq!(parse("=IMAGE(A2)"), Ok(None));
+        assert_eq!(parse("=IMAGE('Other sheet'!A2)"), Ok(None));
+        assert_eq!(parse(r#"=IMAGE(CONCATENATE("https://", "example.com"))"#), Ok(None));
+        assert_eq!(parse(r#"=HYPERLINK("https://example.com/" & A2, "Example")"#), Ok(None));
+        assert_eq!(parse(r#"=IMAGE("a.png", 4, B1, 64)"#), Ok(None));
+        assert_eq!(parse(r#"=IMAGE("a.png", 2 + 2)"#), Ok(None));
+        assert_eq!(parse(r#"=IMAGE("a.png", 4, 64-1, 64)"#), Ok(None));
+        assert_eq!(parse(r#"=IMAGE("a.png", 4, -B1, 64)"#), Ok(None));
+        assert_eq!(parse(r#"=IMAGE("a.png", 4, 1.5.5, 64)"#), Ok(None));
+        assert_eq!(parse(r#"=IMAGE("a.png") & """#), Ok(None));
+    }
+
+    #[test]
+    fn invalid_arguments_are_errors() {
+        assert!(parse("=IMAGE()").is_err());
+        assert!(parse("=IMAGE(1)").is_err());
+        assert!(parse(r#"=IMAGE("a.png", "4")"#).is_err());
+        assert!(parse(r#"=IMAGE("a.png", 0)"#).is_err());
+        assert!(parse(r#"=IMAGE("a.png", 5)"#).is_err());
+        assert!(parse(r#"=IMAGE("a.png", 1.5)"#).is_err());
+        assert!(parse(r#"=IMAGE("a.png", 4, 64, 64, 1)"#).is_err());
+        assert!(parse(r#"=HYPERLINK("https://example.com", "Example", "")"#).is_err());
+        assert!(parse(r#"=HYPERLINK("https://example.com", 1)"#).is_err());
+    }
+
+    #[test]
+    fn incomplete_formulas_are_errors() {
+        assert!(parse("=IMAGE").is_err());
+        assert!(parse(r#"=IMAGE("a.png""#).is_err());
+        assert!(parse(r#"=IMAGE("a.png)"#).is_err());
+        assert!(parse(r#"=IMAGE("a.png","#).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::str::FromStr;
+use anyhow::{bail, Error, Result};
+
+/// Strategy for turning column titles into json keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColumnNaming {
+    /// Lowercase and translate spaces to `_`, e.g. `Buy (Bells)` => `buy_(bells)`
+    #[default]
+    Legacy,
+    /// Lowercase words joined by `_`, without punctuation, e.g. `Buy (Bells)` => `buy_bells`
+    SnakeCase,
+    /// Words without punctuation in camel case, e.g. `Buy (Bells)` => `buyBells`
+    CamelCase,
+    /// Keep titles as they are, e.g. `Buy (Bells)` => `Buy (Bells)`
+    Verbatim,
+}
+
+impl ColumnNaming {
+    /// Returns the column name for the titles of all header rows of a column.
+    pub fn column_name(self, titles: &[impl AsRef<str>]) -> String {
+        match self {
+            ColumnNaming::Legacy => join(titles.iter().map(|title| legacy(title.as_ref())), "_"),
+            ColumnNaming::Verbatim => join(titles.iter().map(|title| title.as_ref().to_owned()), "_"),
+            ColumnNaming::SnakeCase => {
+                let words = words(titles).map(|word| word.to_lowercase());
+                identifier(join(words, "_"))
+            },
+            ColumnNaming::CamelCase => {
+                let words = words(titles)
+                    .enumerate()
+                    .map(|(i, word)| match i {
+                        0 => word.to_lowercase(),
+                        _ => capitalize(word),
+                    });
+                identifier(join(words, ""))
+            },
+        }
+    }
+}
+
+impl FromStr for ColumnNaming {
+    type Err = Error;
+
+    fn from_str(naming: &str) -> Result<Self> {
+        match naming {
+            "legacy" => Ok(ColumnNaming::Legacy),
+            "snake" => Ok(ColumnNaming::SnakeCase),
+            "camel" => Ok(ColumnNaming::CamelCase),
+            "verbatim" => Ok(ColumnNaming::Verbatim),
+            _ => bail!("Unknown column naming '{}', expected 'legacy', 'snake', 'camel' or 'verbatim'", naming),
+        }
+    }
+}
+
+fn legacy(title: &str) -> String {
+    title
+    .chars()
+    .map(|c| match c {
+        ' ' => '_',
+        c => c.to_ascii_lowercase(),
+    })
+    .collect()
+}
+
+/// Splits the titles into words of alphanumeric characters.
+fn words(titles: &[impl AsRef<str>]) -> impl Iterator<Item = &str> {
+    titles.iter()
+        .flat_map(|title| title.as_ref().split(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+        None => String::new(),
+    }
+}
+
+/// Prefixes names starting with a digit with `_`.
+fn identifier(name: String) -> String {
+    match name.chars().next() {
+        Some(c) if c.is_ascii_digit() => format!("_{}", name),
+        _ => name,
+    }
+}
+
+fn join(parts: impl Iterator<Item = String>, separator: &str) -> String {
+    parts.collect::<Vec<_>>().join(separator)
+}
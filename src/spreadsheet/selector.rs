@@ -0,0 +1,171 @@
+use super::{Sheet, SheetProperties};
+
+/// Selects sheets by title pattern, gid or index.
+///
+/// A sheet is selected if it matches any of the included titles, gids or indices
+/// (or if nothing is included explicitly) and none of the excluded titles.
+/// Title patterns may contain `*` (any number of characters) and `?` (a single character).
+#[derive(Debug, Clone, Default)]
+pub struct SheetSelector {
+    titles: Vec<String>,
+    gids: Vec<i64>,
+    indices: Vec<usize>,
+    excluded_titles: Vec<String>,
+}
+
+impl SheetSelector {
+    /// Returns a selector that selects all sheets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn title(mut self, pattern: impl Into<String>) -> Self {
+        self.titles.push(pattern.into());
+        self
+    }
+
+    pub fn gid(mut self, gid: i64) -> Self {
+        self.gids.push(gid);
+        self
+    }
+
+    pub fn index(mut self, index: usize) -> Self {
+        self.indices.push(index);
+        self
+    }
+
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.excluded_titles.push(pattern.into());
+        self
+    }
+
+    pub fn matches(&self, sheet: &Sheet) -> bool {
+        self.matches_properties(&sheet.properties)
+    }
+
+    pub fn matches_properties(&self, properties: &SheetProperties) -> bool {
+        let includes_all = self.titles.is_empty() && self.gids.is_empty() && self.indices.is_empty();
+        let included = includes_all
+            || self.titles.iter().any(|pattern| glob_matches(pattern, &properties.title))
+            || self.gids.contains(&properties.sheet_id)
+            || self.indices.contains(&properties.index);
+        let excluded = self.excluded_titles.iter().any(|pattern| glob_matches(pattern, &properties.title));
+
+        included && !excluded
+    }
+}
+
+/// Matches `text` against a pattern containing `*` and `?` wildcards.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it was matched at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                // Let the last `*` match one more character
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(title: &str, sheet_id: i64, index: usize) -> SheetProperties {
+        serde_json::from_value(serde_json::json!({ "title": title, "sheetId": sheet_id, "index": index })).unwrap()
+    }
+
+    #[test]
+    fn matches_literal_patterns() {
+        assert!(glob_matches("Housewares", "Housewares"));
+        assert!(!glob_matches("Housewares", "housewares"));
+        assert!(!glob_matches("Housewares", "Housewares 2"));
+        assert!(!glob_matches("Housewares 2", "Housewares"));
+    }
+
+    #[test]
+    fn matches_empty_patterns() {
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "Fish"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("**", "Fish"));
+        assert!(!glob_matches("?", ""));
+    }
+
+    #[test]
+    fn matches_trailing_star() {
+        assert!(glob_matches("Wall*", "Wall"));
+        assert!(glob_matches("Wall*", "Wallpaper"));
+        assert!(glob_matches("Wall*", "Wall-mounted"));
+        assert!(!glob_matches("Wall*", "Rugs"));
+        assert!(!glob_matches("Wall*", "Art Wall"));
+    }
+
+    #[test]
+    fn star_backtracks() {
+        assert!(glob_matches("*Old*", "Fish (Old)"));
+        assert!(glob_matches("*a*b", "aXbYab"));
+        assert!(!glob_matches("*a*b", "aXbYa"));
+        assert!(glob_matches("W*l*r", "Wallpaper"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(glob_matches("Fish ?", "Fish 2"));
+        assert!(!glob_matches("Fish ?", "Fish 10"));
+        assert!(!glob_matches("Fish ?", "Fish "));
+    }
+
+    #[test]
+    fn matches_multibyte_titles_by_character() {
+        assert!(glob_matches("Caf?", "Café"));
+        assert!(glob_matches("*é", "Café"));
+        assert!(glob_matches("??", "日本"));
+        assert!(!glob_matches("?", "日本"));
+    }
+
+    #[test]
+    fn selects_all_sheets_by_default() {
+        assert!(SheetSelector::new().matches_properties(&properties("Fish", 1, 0)));
+    }
+
+    #[test]
+    fn selects_by_title_gid_or_index() {
+        let selector = SheetSelector::new().title("Wall*").gid(7).index(3);
+
+        assert!(selector.matches_properties(&properties("Wallpaper", 1, 0)));
+        assert!(selector.matches_properties(&properties("Fish", 7, 0)));
+        assert!(selector.matches_properties(&properties("Bugs", 1, 3)));
+        assert!(!selector.matches_properties(&properties("Fish", 1, 0)));
+    }
+
+    #[test]
+    fn exclusions_take_precedence() {
+        let selector = SheetSelector::new().title("Wall*").exclude("Wallpaper");
+
+        assert!(selector.matches_properties(&properties("Wall-mounted", 1, 0)));
+        assert!(!selector.matches_properties(&properties("Wallpaper", 2, 1)));
+        assert!(!SheetSelector::new().exclude("*").matches_properties(&properties("Fish", 1, 0)));
+    }
+}
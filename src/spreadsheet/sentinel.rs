@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use serde_json as json;
+
+/// Rules for replacing placeholder strings like `NA` or `-` with other json values.
+///
+/// Rules for a column take precedence over global rules.
+///
+/// Example:
+/// ```json
+/// {
+///     "global": { "NA": null, "Yes": true, "No": false },
+///     "columns": {
+///         "buy": { "NFS": null }
+///     }
+/// }
+/// ```
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sentinels {
+    /// Replacements for every column, keyed by placeholder
+    pub global: HashMap<String, json::Value>,
+    /// Replacements for specific columns, keyed by column and placeholder
+    pub columns: HashMap<String, HashMap<String, json::Value>>,
+}
+
+impl Sentinels {
+    /// The placeholders used by the datamine:
+    /// `NA`, `None` and `-` become `null`, `Yes` and `No` become booleans.
+    pub fn datamine() -> Self {
+        let global = vec![
+            ("NA", json::Value::Null),
+            ("None", json::Value::Null),
+            ("-", json::Value::Null),
+            ("Yes", json::Value::Bool(true)),
+            ("No", json::Value::Bool(false)),
+        ];
+
+        Self {
+            global: global.into_iter().map(|(text, value)| (text.to_owned(), value)).collect(),
+            columns: HashMap::new(),
+        }
+    }
+
+    /// Adds the rules of `other`, replacing existing rules for the same placeholders.
+    pub fn extend(&mut self, other: Sentinels) {
+        self.global.extend(other.global);
+
+        for (column, rules) in other.columns {
+            self.columns.entry(column).or_default().extend(rules);
+        }
+    }
+
+    /// Returns the replacement for `text` in the given column, if any.
+    pub fn replacement(&self, column: &str, text: &str) -> Option<&json::Value> {
+        self.columns.get(column)
+            .and_then(|rules| rules.get(text))
+            .or_else(|| self.global.get(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn column_rules_take_precedence() {
+        let sentinels = json::from_value::<Sentinels>(json!({
+            "global": { "NA": null, "-": "" },
+            "columns": { "buy": { "NA": 0 } },
+        }))
+        .unwrap();
+
+        assert_eq!(sentinels.replacement("buy", "NA"), Some(&json!(0)));
+        assert_eq!(sentinels.replacement("buy", "-"), Some(&json!("")));
+        assert_eq!(sentinels.replacement("sell", "NA"), Some(&json::Value::Null));
+        assert_eq!(sentinels.replacement("sell", "na"), None);
+    }
+
+    #[test]
+    fn extend_replaces_rules() {
+        let mut sentinels = Sentinels::datamine();
+        sentinels.extend(json::from_value(json!({
+            "global": { "Yes": "yes", "n/a": null },
+            "columns": { "diy": { "No": 0 } },
+        }))
+        .unwrap());
+
+        assert_eq!(sentinels.replacement("name", "Yes"), Some(&json!("yes")));
+        assert_eq!(sentinels.replacement("name", "n/a"), Some(&json::Value::Null));
+        assert_eq!(sentinels.replacement("name", "NA"), Some(&json::Value::Null));
+        assert_eq!(sentinels.replacement("diy", "No"), Some(&json!(0)));
+        assert_eq!(sentinels.replacement("name", "No"), Some(&json!(false)));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(json::from_value::<Sentinels>(json!({ "globals": {} })).is_err());
+    }
+}
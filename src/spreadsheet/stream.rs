@@ -0,0 +1,359 @@
+//! Parses spreadsheets one sheet or row at a time,
+//! so that at most a single sheet has to be kept in memory.
+
+use std::{fmt, io::Read};
+use anyhow::{Context, Result};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use super::{GridData, RowData, Sheet, SheetProperties, SheetSelector, Spreadsheet};
+
+type SheetCallback<'a> = dyn FnMut(Sheet) -> Result<()> + 'a;
+type RowCallback<'a> = dyn FnMut(&SheetProperties, StreamedRow) -> Result<()> + 'a;
+
+/// A row passed to the callback of `Spreadsheet::for_each_row`.
+#[derive(Debug)]
+pub struct StreamedRow {
+    /// Zero based index of the row within its sheet
+    pub index: usize,
+    /// Zero based index of the column of the row's first cell
+    pub start_column: usize,
+    pub data: RowData,
+}
+
+impl Spreadsheet {
+    /// Parses a spreadsheet from `reader` and passes each sheet to `on_sheet`
+    /// as soon as it has been parsed.
+    pub fn for_each_sheet(reader: impl Read, mut on_sheet: impl FnMut(Sheet) -> Result<()>) -> Result<()> {
+        stream(reader, Callbacks {
+            on_sheet: &mut on_sheet,
+            on_row: None,
+            selector: None,
+            error: None,
+        })
+    }
+
+    /// Like `for_each_sheet`, but only passes sheets matched by `selector`.
+    /// The rows of other sheets are skipped without parsing them.
+    pub fn for_each_selected_sheet(
+        reader: impl Read,
+        selector: &SheetSelector,
+        mut on_sheet: impl FnMut(Sheet) -> Result<()>,
+    ) -> Result<()> {
+        stream(reader, Callbacks {
+            on_sheet: &mut on_sheet,
+            on_row: None,
+            selector: Some(selector),
+            error: None,
+        })
+    }
+
+    /// Like `for_each_selected_sheet`, but passes every row to `on_row` as soon as it has been parsed,
+    /// together with the properties of its sheet.
+    /// The rows are not kept, so sheets passed to `on_sheet` contain no rows.
+    ///
+    /// The Sheets API sends the properties of a sheet before its data
+    /// and the offsets of grid data before its rows.
+    /// Other input fails instead of passing rows with wrong indices.
+    pub fn for_each_row(
+        reader: impl Read,
+        selector: &SheetSelector,
+        mut on_row: impl FnMut(&SheetProperties, StreamedRow) -> Result<()>,
+        mut on_sheet: impl FnMut(Sheet) -> Result<()>,
+    ) -> Result<()> {
+        stream(reader, Callbacks {
+            on_sheet: &mut on_sheet,
+            on_row: Some(&mut on_row),
+            selector: Some(selector),
+            error: None,
+        })
+    }
+}
+
+fn stream(reader: impl Read, mut callbacks: Callbacks) -> Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+
+    let result = SpreadsheetSeed { callbacks: &mut callbacks }
+        .deserialize(&mut deserializer)
+        .and_then(|()| deserializer.end());
+
+    // Errors of callbacks are smuggled through serde as a generic message
+    if let Some(error) = callbacks.error {
+        return Err(error);
+    }
+
+    result.context("Failed deserializing spreadsheet")
+}
+
+struct Callbacks<'a> {
+    on_sheet: &'a mut SheetCallback<'a>,
+    on_row: Option<&'a mut RowCallback<'a>>,
+    selector: Option<&'a SheetSelector>,
+    /// The first error returned by a callback
+    error: Option<anyhow::Error>,
+}
+
+impl Callbacks<'_> {
+    /// Returns whether the sheet with the given properties is selected.
+    /// Sheets are assumed to be selected as long as their properties are unknown.
+    fn is_selected(&self, properties: Option<&SheetProperties>) -> bool {
+        match (self.selector, properties) {
+            (Some(selector), Some(properties)) => selector.matches_properties(properties),
+            _ => true,
+        }
+    }
+
+    fn fail<E: de::Error>(&mut self, error: anyhow::Error) -> E {
+        self.error = Some(error);
+        E::custom("callback failed")
+    }
+}
+
+struct SpreadsheetSeed<'c, 'a> {
+    callbacks: &'c mut Callbacks<'a>,
+}
+
+impl<'de> DeserializeSeed<'de> for SpreadsheetSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SpreadsheetSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a spreadsheet")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "sheets" => map.next_value_seed(SheetsSeed { callbacks: &mut *self.callbacks })?,
+                _ => { map.next_value::<IgnoredAny>()?; },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct SheetsSeed<'c, 'a> {
+    callbacks: &'c mut Callbacks<'a>,
+}
+
+impl<'de> DeserializeSeed<'de> for SheetsSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SheetsSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of sheets")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element_seed(SheetSeed { callbacks: &mut *self.callbacks })?.is_some() {}
+
+        Ok(())
+    }
+}
+
+struct SheetSeed<'c, 'a> {
+    callbacks: &'c mut Callbacks<'a>,
+}
+
+impl<'de> DeserializeSeed<'de> for SheetSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SheetSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sheet")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut properties = None;
+        let mut data = Vec::new();
+        let mut merges = Vec::new();
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "properties" => properties = Some(map.next_value()?),
+                "merges" => merges = map.next_value()?,
+                "data" if !self.callbacks.is_selected(properties.as_ref()) => {
+                    map.next_value::<IgnoredAny>()?;
+                },
+                "data" if self.callbacks.on_row.is_some() => {
+                    let properties = properties.as_ref()
+                        .ok_or_else(|| de::Error::custom("Expected the properties of a sheet before its data"))?;
+
+                    data = map.next_value_seed(GridDataListSeed {
+                        callbacks: &mut *self.callbacks,
+                        properties,
+                    })?;
+                },
+                "data" => data = map.next_value()?,
+                _ => { map.next_value::<IgnoredAny>()?; },
+            }
+        }
+
+        let properties = properties.ok_or_else(|| de::Error::missing_field("properties"))?;
+
+        if !self.callbacks.is_selected(Some(&properties)) {
+            return Ok(());
+        }
+
+        let sheet = Sheet {
+            properties,
+            data,
+            merges,
+        };
+
+        (self.callbacks.on_sheet)(sheet)
+            .map_err(|error| self.callbacks.fail(error))
+    }
+}
+
+struct GridDataListSeed<'c, 'a, 'p> {
+    callbacks: &'c mut Callbacks<'a>,
+    properties: &'p SheetProperties,
+}
+
+impl<'de> DeserializeSeed<'de> for GridDataListSeed<'_, '_, '_> {
+    type Value = Vec<GridData>;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for GridDataListSeed<'_, '_, '_> {
+    type Value = Vec<GridData>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of grid data")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut data = Vec::new();
+
+        while let Some(grid_data) = seq.next_element_seed(GridDataSeed {
+            callbacks: &mut *self.callbacks,
+            properties: self.properties,
+        })? {
+            data.push(grid_data);
+        }
+
+        Ok(data)
+    }
+}
+
+struct GridDataSeed<'c, 'a, 'p> {
+    callbacks: &'c mut Callbacks<'a>,
+    properties: &'p SheetProperties,
+}
+
+impl<'de> DeserializeSeed<'de> for GridDataSeed<'_, '_, '_> {
+    type Value = GridData;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for GridDataSeed<'_, '_, '_> {
+    type Value = GridData;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "grid data")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut grid_data = GridData {
+            start_row: 0,
+            start_column: 0,
+            row_data: Vec::new(),
+        };
+        // Offsets of 0 are omitted, so rows are passed on before knowing whether offsets follow
+        let mut rows_passed = false;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "startRow" | "startColumn" if rows_passed => {
+                    if map.next_value::<usize>()? != 0 {
+                        return Err(de::Error::custom("Expected the offsets of grid data before its rows"));
+                    }
+                },
+                "startRow" => grid_data.start_row = map.next_value()?,
+                "startColumn" => grid_data.start_column = map.next_value()?,
+                "rowData" => {
+                    map.next_value_seed(RowsSeed {
+                        callbacks: &mut *self.callbacks,
+                        properties: self.properties,
+                        start_row: grid_data.start_row,
+                        start_column: grid_data.start_column,
+                    })?;
+                    rows_passed = true;
+                },
+                _ => { map.next_value::<IgnoredAny>()?; },
+            }
+        }
+
+        Ok(grid_data)
+    }
+}
+
+struct RowsSeed<'c, 'a, 'p> {
+    callbacks: &'c mut Callbacks<'a>,
+    properties: &'p SheetProperties,
+    start_row: usize,
+    start_column: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for RowsSeed<'_, '_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RowsSeed<'_, '_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of rows")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut index = self.start_row;
+
+        while let Some(data) = seq.next_element::<RowData>()? {
+            let row = StreamedRow {
+                index,
+                start_column: self.start_column,
+                data,
+            };
+
+            if let Some(on_row) = &mut self.callbacks.on_row {
+                on_row(self.properties, row).map_err(|error| self.callbacks.fail(error))?;
+            }
+
+            index += 1;
+        }
+
+        Ok(())
+    }
+}
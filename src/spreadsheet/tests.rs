@@ -0,0 +1,1168 @@
+//! Tests for converting sheets to json rows, using small sheets built in code.
+
+use serde_json::{json, Value};
+use super::*;
+
+fn text(value: &str) -> Value {
+    json!({
+        "userEnteredValue": { "stringValue": value },
+        "effectiveValue": { "stringValue": value },
+    })
+}
+
+fn number(value: f64) -> Value {
+    json!({
+        "userEnteredValue": { "numberValue": value },
+        "effectiveValue": { "numberValue": value },
+    })
+}
+
+fn boolean(value: bool) -> Value {
+    json!({
+        "userEnteredValue": { "boolValue": value },
+        "effectiveValue": { "boolValue": value },
+    })
+}
+
+/// Adds `fields` like `note` or `hyperlink` to `cell`.
+fn with(mut cell: Value, fields: Value) -> Value {
+    if let (Some(cell), Value::Object(fields)) = (cell.as_object_mut(), fields) {
+        cell.extend(fields);
+    }
+
+    cell
+}
+
+/// Returns a sheet titled `Test` with the given rows in a single grid data block.
+fn sheet(rows: &[Vec<Value>]) -> Sheet {
+    sheet_with(rows, json!({}))
+}
+
+/// Like `sheet`, but with additional `fields` of the sheet like `merges`.
+fn sheet_with(rows: &[Vec<Value>], fields: Value) -> Sheet {
+    let rows = rows.iter()
+        .map(|values| json!({ "values": values }))
+        .collect::<Vec<_>>();
+    let sheet = with(
+        json!({
+            "properties": { "title": "Test", "sheetId": 7, "index": 2 },
+            "data": [{ "rowData": rows }],
+        }),
+        fields,
+    );
+
+    json::from_value(sheet).unwrap()
+}
+
+/// Converts the sheet and returns the rows as json values.
+fn rows(sheet: &Sheet, options: &JsonOptions) -> Vec<Value> {
+    sheet.json_rows_with(options).unwrap()
+        .rows
+        .into_iter()
+        .map(Value::Object)
+        .collect()
+}
+
+#[test]
+fn exports_values_as_strings_by_default() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy"), text("Sell"), text("Fish")],
+        vec![text("Carp"), number(300.), number(1.5), boolean(true)],
+    ]);
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [
+        json!({ "name": "Carp", "buy": "300", "sell": "1.5", "fish": "true" }),
+    ]);
+}
+
+#[test]
+fn typed_values_exports_numbers_and_booleans() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy"), text("Sell"), text("Fish")],
+        vec![text("Carp"), number(300.), number(1.5), boolean(false)],
+    ]);
+    let options = JsonOptions {
+        typed_values: true,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "buy": 300, "sell": 1.5, "fish": false }),
+    ]);
+}
+
+#[test]
+fn skips_rows_without_values() {
+    let sheet = sheet(&[
+        vec![text("Name")],
+        vec![],
+        vec![json!({})],
+        vec![text("Carp")],
+    ]);
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "name": "Carp" })]);
+}
+
+/// A cell whose effective value is a formula, which can't be exported.
+fn undecodable() -> Value {
+    json!({ "effectiveValue": { "formulaValue": "=A1" } })
+}
+
+#[test]
+fn undecodable_cell_fails_with_its_location() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), undecodable()],
+    ]);
+
+    let error = sheet.json_rows_with(&JsonOptions::default()).unwrap_err();
+    let error = error.downcast_ref::<CellError>().unwrap();
+
+    assert_eq!(error.sheet, "Test");
+    assert_eq!(error.row, 1);
+    assert_eq!(error.column, "buy");
+    assert_eq!(error.error.kind, DecodeErrorKind::UnsupportedValue);
+}
+
+#[test]
+fn lenient_skips_undecodable_cell_with_warning() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), undecodable()],
+    ]);
+    let options = JsonOptions {
+        strictness: Strictness::Lenient,
+        ..JsonOptions::default()
+    };
+
+    let json_rows = sheet.json_rows_with(&options).unwrap();
+
+    assert_eq!(json_rows.rows.len(), 1);
+    assert_eq!(Value::Object(json_rows.rows[0].clone()), json!({ "name": "Carp" }));
+    assert_eq!(json_rows.warnings.len(), 1);
+    assert_eq!((json_rows.warnings[0].row, json_rows.warnings[0].column.as_str()), (1, "buy"));
+}
+
+#[test]
+fn cell_error_has_decode_error_as_source() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), undecodable()],
+    ]);
+
+    let error = sheet.json_rows_with(&JsonOptions::default()).unwrap_err();
+
+    assert!(error.chain().any(|cause| cause.is::<DecodeError>()));
+    assert!(format!("{:#}", error).ends_with(": Unsupported value: =A1"));
+}
+
+fn error_value(error_type: &str, formula: &str) -> Value {
+    json!({
+        "userEnteredValue": { "formulaValue": formula },
+        "effectiveValue": { "errorValue": { "type": error_type, "message": "Not found" } },
+    })
+}
+
+#[test]
+fn exports_error_values_as_null_and_reports_them() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), error_value("N_A", "=VLOOKUP(A2, B:B, 1)")],
+    ]);
+
+    let json_rows = sheet.json_rows_with(&JsonOptions::default()).unwrap();
+
+    assert_eq!(Value::Object(json_rows.rows[0].clone()), json!({ "name": "Carp", "buy": null }));
+    assert_eq!(json::to_value(&json_rows.formula_errors).unwrap(), json!([{
+        "row": 1,
+        "column": "buy",
+        "formula": "=VLOOKUP(A2, B:B, 1)",
+        "error": { "type": "N_A", "message": "Not found" },
+    }]));
+}
+
+#[test]
+fn tagged_error_values() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), error_value("REF", "=#REF!")],
+    ]);
+    let options = JsonOptions {
+        error_values: ErrorValueMode::Tagged,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "buy": { "error": "#REF!", "message": "Not found" } }),
+    ]);
+}
+
+fn linked(value: &str, url: &str) -> Value {
+    with(text(value), json!({ "hyperlink": url }))
+}
+
+#[test]
+fn ignores_links_by_default() {
+    let sheet = sheet(&[
+        vec![text("Name")],
+        vec![linked("Carp", "https://example.com/carp")],
+    ]);
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "name": "Carp" })]);
+}
+
+#[test]
+fn exports_links_as_objects() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Source")],
+        vec![
+            linked("Carp", "https://example.com/carp"),
+            with(text("Wiki and more"), json!({
+                "textFormatRuns": [
+                    { "format": {} },
+                    { "startIndex": 5, "format": { "link": { "uri": "https://example.com/wiki" } } },
+                ],
+            })),
+        ],
+    ]);
+    let options = JsonOptions {
+        links: LinkMode::Object,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [json!({
+        "name": { "text": "Carp", "url": "https://example.com/carp" },
+        "source": { "text": "Wiki and more", "url": "https://example.com/wiki" },
+    })]);
+}
+
+#[test]
+fn exports_links_as_siblings() {
+    let sheet = sheet(&[
+        vec![text("Name")],
+        vec![with(text("Carp"), json!({
+            "userEnteredValue": { "formulaValue": "=HYPERLINK(\"https://example.com/carp\", \"Carp\")" },
+        }))],
+    ]);
+    let options = JsonOptions {
+        links: LinkMode::Sibling,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "name_url": "https://example.com/carp" }),
+    ]);
+}
+
+#[test]
+fn sibling_links_never_replace_columns() {
+    let options = JsonOptions {
+        links: LinkMode::Sibling,
+        ..JsonOptions::default()
+    };
+
+    // The column with the key of the links both before and after the linked column
+    for titles in &[["Name URL", "Name"], ["Name", "Name URL"]] {
+        let linked_column = titles.iter().position(|&title| title == "Name").unwrap();
+        let row = |name: &str| {
+            let mut row = vec![text("Column"), text("Column")];
+            row[linked_column] = linked(name, "https://example.com");
+            row
+        };
+        let sheet = sheet(&[
+            titles.iter().map(|title| text(title)).collect(),
+            row("Carp"),
+            row("Koi"),
+        ]);
+
+        let json_rows = sheet.json_rows_with(&options).unwrap();
+
+        assert_eq!(Value::Object(json_rows.rows[0].clone()), json!({ "name": "Carp", "name_url": "Column" }));
+        assert_eq!(Value::Object(json_rows.rows[1].clone()), json!({ "name": "Koi", "name_url": "Column" }));
+        // Reported only once
+        assert_eq!(json_rows.warnings.len(), 1);
+        assert_eq!(json_rows.warnings[0].column, "name");
+        assert_eq!(json_rows.warnings[0].error.kind, DecodeErrorKind::KeyCollision);
+        assert_eq!(json_rows.warnings[0].error.raw_value, "name_url");
+    }
+}
+
+#[test]
+fn rows_are_serialized_with_sorted_keys() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy"), text("Color")],
+        vec![text("Carp"), text("300"), text("Red")],
+    ]);
+
+    let json_rows = sheet.json_rows_with(&JsonOptions::default()).unwrap();
+
+    assert_eq!(
+        json::to_string(&json_rows.rows[0]).unwrap(),
+        r#"{"buy":"300","color":"Red","name":"Carp"}"#,
+    );
+}
+
+#[test]
+fn ordered_rows_are_serialized_in_column_order() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy"), text("Color")],
+        vec![linked("Carp", "https://example.com/carp"), with(text("300"), json!({ "note": "Cheap" })), text("Red")],
+    ]);
+    let options = JsonOptions {
+        links: LinkMode::Sibling,
+        notes: true,
+        keep_column_order: true,
+        ..JsonOptions::default()
+    };
+
+    let json_rows = sheet.json_rows_with(&options).unwrap();
+    let row = OrderedRow::new(&json_rows.rows[0], &json_rows.columns);
+
+    assert_eq!(
+        json::to_string(&row).unwrap(),
+        r#"{"name":"Carp","name_url":"https://example.com/carp","buy":"300","color":"Red","_notes":{"buy":"Cheap"}}"#,
+    );
+}
+
+#[test]
+fn exports_notes_keyed_by_column() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), with(number(300.), json!({ "note": "Only in spring" }))],
+    ]);
+    let options = JsonOptions {
+        notes: true,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [json!({
+        "name": "Carp",
+        "buy": "300",
+        "_notes": { "buy": "Only in spring" },
+    })]);
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "name": "Carp", "buy": "300" })]);
+}
+
+/// A cell with a formula and an empty effective value, like cells containing an `IMAGE`.
+fn formula(formula: &str) -> Value {
+    json!({ "userEnteredValue": { "formulaValue": formula } })
+}
+
+#[test]
+fn exports_image_urls_and_metadata() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Image")],
+        vec![text("Carp"), formula(r#"=IMAGE("https://example.com/carp.png", 4, 64, 32)"#)],
+        vec![text("Koi"), formula("=IMAGE(C3)")],
+    ]);
+    let options = JsonOptions {
+        image_metadata: true,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({
+            "name": "Carp",
+            "image": "https://example.com/carp.png",
+            "image_image_meta": { "mode": 4, "height": 64.0, "width": 32.0 },
+        }),
+        // Formulas with non-literal arguments fall back to the effective value
+        json!({ "name": "Koi", "image": null }),
+    ]);
+}
+
+#[test]
+fn image_metadata_never_replaces_columns() {
+    let sheet = sheet(&[
+        vec![text("Icon"), text("Icon Image Meta")],
+        vec![formula(r#"=IMAGE("carp.png", 2)"#), text("carp.png")],
+    ]);
+    let options = JsonOptions {
+        image_metadata: true,
+        ..JsonOptions::default()
+    };
+
+    let json_rows = sheet.json_rows_with(&options).unwrap();
+
+    assert_eq!(Value::Object(json_rows.rows[0].clone()), json!({ "icon": "carp.png", "icon_image_meta": "carp.png" }));
+    assert_eq!(json_rows.warnings[0].error.kind, DecodeErrorKind::KeyCollision);
+}
+
+/// Returns a merge of the given zero based, end exclusive rows and columns.
+fn merge(rows: Range<usize>, columns: Range<usize>) -> Value {
+    json!({
+        "startRowIndex": rows.start,
+        "endRowIndex": rows.end,
+        "startColumnIndex": columns.start,
+        "endColumnIndex": columns.end,
+    })
+}
+
+#[test]
+fn merged_cells_are_only_filled_if_enabled() {
+    let sheet = sheet_with(
+        &[
+            vec![text("Name"), text("Season")],
+            vec![text("Carp"), text("Spring")],
+            vec![text("Koi")],
+        ],
+        json!({ "merges": [merge(1..3, 1..2)] }),
+    );
+    let options = JsonOptions {
+        fill_merged: true,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [
+        json!({ "name": "Carp", "season": "Spring" }),
+        json!({ "name": "Koi" }),
+    ]);
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "season": "Spring" }),
+        json!({ "name": "Koi", "season": "Spring" }),
+    ]);
+}
+
+#[test]
+fn merged_cells_fill_rows_and_columns() {
+    let sheet = sheet_with(
+        &[
+            vec![text("Name"), text("Buy"), text("Sell")],
+            vec![text("Carp"), text("NFS")],
+            vec![text("Koi")],
+        ],
+        json!({ "merges": [merge(1..3, 1..3)] }),
+    );
+    let options = JsonOptions {
+        fill_merged: true,
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "buy": "NFS", "sell": "NFS" }),
+        json!({ "name": "Koi", "buy": "NFS", "sell": "NFS" }),
+    ]);
+}
+
+#[test]
+fn merged_cells_report_errors_and_notes_only_once() {
+    let sheet = sheet_with(
+        &[
+            vec![text("Name"), text("Buy")],
+            vec![text("Carp"), with(error_value("N_A", "=VLOOKUP(A2, B:B, 1)"), json!({ "note": "Broken" }))],
+            vec![text("Koi")],
+            vec![text("Pike")],
+        ],
+        json!({ "merges": [merge(1..4, 1..2)] }),
+    );
+    let options = JsonOptions {
+        fill_merged: true,
+        notes: true,
+        error_values: ErrorValueMode::Tagged,
+        ..JsonOptions::default()
+    };
+
+    let json_rows = sheet.json_rows_with(&options).unwrap();
+    let error = json!({ "error": "#N/A", "message": "Not found" });
+
+    assert_eq!(json_rows.rows.into_iter().map(Value::Object).collect::<Vec<_>>(), [
+        json!({ "name": "Carp", "buy": error, "_notes": { "buy": "Broken" } }),
+        json!({ "name": "Koi", "buy": error }),
+        json!({ "name": "Pike", "buy": error }),
+    ]);
+    assert_eq!(json_rows.formula_errors.len(), 1);
+    assert_eq!((json_rows.formula_errors[0].row, json_rows.formula_errors[0].column.as_str()), (1, "buy"));
+}
+
+#[test]
+fn merged_cells_report_decode_errors_only_once() {
+    let sheet = sheet_with(
+        &[
+            vec![text("Name"), text("Buy")],
+            vec![text("Carp"), undecodable()],
+            vec![text("Koi")],
+        ],
+        json!({ "merges": [merge(1..3, 1..2)] }),
+    );
+    let options = JsonOptions {
+        fill_merged: true,
+        strictness: Strictness::Lenient,
+        ..JsonOptions::default()
+    };
+
+    let json_rows = sheet.json_rows_with(&options).unwrap();
+
+    assert_eq!(json_rows.rows.len(), 2);
+    assert_eq!(json_rows.warnings.len(), 1);
+}
+
+#[test]
+fn header_row_can_be_moved() {
+    let sheet = sheet(&[
+        vec![text("Fish of the month")],
+        vec![text("Name")],
+        vec![text("Carp")],
+    ]);
+    let options = JsonOptions {
+        header: Header { row: 1, ..Header::default() },
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [json!({ "name": "Carp" })]);
+}
+
+#[test]
+fn multiple_header_rows_are_joined() {
+    let sheet = sheet_with(
+        &[
+            vec![text("Name"), text("NH"), json!({}), text("SH")],
+            vec![json!({}), text("Jan"), text("Feb"), text("Jan")],
+            vec![text("Carp"), boolean(true), boolean(false), boolean(true)],
+        ],
+        // The group title `NH` is merged across both of its columns
+        json!({ "merges": [merge(0..1, 1..3)] }),
+    );
+    let options = JsonOptions {
+        header: Header { rows: 2, ..Header::default() },
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(sheet.column_keys(&options).unwrap(), ["name", "nh_jan", "nh_feb", "sh_jan"]);
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "nh_jan": "true", "nh_feb": "false", "sh_jan": "true" }),
+    ]);
+}
+
+#[test]
+fn header_can_be_detected_from_frozen_rows() {
+    let rows_data = [
+        vec![text("Name"), text("Price")],
+        vec![json!({}), text("Buy")],
+        vec![text("Carp"), number(300.)],
+    ];
+    let frozen = sheet_with(&rows_data, json!({
+        "properties": { "title": "Test", "gridProperties": { "frozenRowCount": 2 } },
+    }));
+    let not_frozen = sheet(&rows_data);
+    let options = JsonOptions {
+        header: Header { detect: true, ..Header::default() },
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(frozen.column_keys(&options).unwrap(), ["name", "price_buy"]);
+    // Falls back to a single header row
+    assert_eq!(not_frozen.column_keys(&options).unwrap(), ["name", "price"]);
+}
+
+#[test]
+fn header_can_be_set_per_sheet() {
+    let sheet = sheet(&[
+        vec![text("Fish of the month")],
+        vec![text("Name")],
+        vec![text("Carp")],
+    ]);
+    let options = JsonOptions {
+        sheet_headers: vec![("Test".to_owned(), Header { row: 1, ..Header::default() })].into_iter().collect(),
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(sheet.column_keys(&options).unwrap(), ["name"]);
+    assert_eq!(sheet.column_keys(&JsonOptions::default()).unwrap(), ["fish_of_the_month"]);
+}
+
+fn strict_column_names() -> JsonOptions {
+    JsonOptions {
+        column_names: ColumnNames::Strict,
+        ..JsonOptions::default()
+    }
+}
+
+#[test]
+fn blank_and_duplicate_titles_are_disambiguated_by_default() {
+    let sheet = sheet(&[
+        vec![text("Name"), text(" "), text("Name"), text("Name 2")],
+        vec![text("Carp"), text("a"), text("b"), text("c"), text("past the header")],
+    ]);
+
+    assert_eq!(sheet.column_keys(&JsonOptions::default()).unwrap(), ["name", "_col_2", "name_3", "name_2"]);
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({
+        "name": "Carp",
+        "_col_2": "a",
+        "name_3": "b",
+        "name_2": "c",
+        "_col_5": "past the header",
+    })]);
+}
+
+#[test]
+fn strict_column_names_reject_blank_and_duplicate_titles() {
+    let duplicate = sheet(&[vec![text("Name"), text("Buy"), text("name")]]);
+    let blank = sheet(&[vec![text("Name"), json!({}), text("Buy")]]);
+
+    let error = duplicate.column_keys(&strict_column_names()).unwrap_err();
+    assert_eq!(error.to_string(), "Sheet 'Test': Columns A and C have the same title 'name'");
+
+    let error = blank.column_keys(&strict_column_names()).unwrap_err();
+    assert_eq!(error.to_string(), "Sheet 'Test': Column B has no title");
+}
+
+#[test]
+fn strict_column_names_reject_values_past_the_header() {
+    let blank = sheet(&[
+        vec![text("Name")],
+        vec![text("Carp"), json!({})],
+    ]);
+    let value = sheet(&[
+        vec![text("Name")],
+        vec![text("Carp"), text("Spring")],
+    ]);
+    let error_value = sheet(&[
+        vec![text("Name")],
+        vec![text("Carp"), error_value("N_A", "=NA()")],
+    ]);
+
+    assert_eq!(rows(&blank, &strict_column_names()), [json!({ "name": "Carp" })]);
+
+    for sheet in &[value, error_value] {
+        let error = sheet.json_rows_with(&strict_column_names()).unwrap_err();
+        assert_eq!(error.to_string(), "Sheet 'Test': Cell B2 has a value, but its column has no title");
+    }
+}
+
+fn naming_sheet() -> Sheet {
+    sheet(&[
+        vec![text("Name"), text("Unique Entry ID"), text("Buy (Bells)"), text("2nd Color")],
+        vec![text("Carp"), text("abc"), number(300.), text("Red")],
+    ])
+}
+
+fn with_naming(column_naming: ColumnNaming) -> JsonOptions {
+    JsonOptions {
+        column_naming,
+        ..JsonOptions::default()
+    }
+}
+
+#[test]
+fn column_naming_strategies() {
+    let sheet = naming_sheet();
+
+    assert_eq!(sheet.column_keys(&with_naming(ColumnNaming::Legacy)).unwrap(), ["name", "unique_entry_id", "buy_(bells)", "2nd_color"]);
+    assert_eq!(sheet.column_keys(&with_naming(ColumnNaming::SnakeCase)).unwrap(), ["name", "unique_entry_id", "buy_bells", "_2nd_color"]);
+    assert_eq!(sheet.column_keys(&with_naming(ColumnNaming::CamelCase)).unwrap(), ["name", "uniqueEntryId", "buyBells", "_2ndColor"]);
+    assert_eq!(sheet.column_keys(&with_naming(ColumnNaming::Verbatim)).unwrap(), ["Name", "Unique Entry ID", "Buy (Bells)", "2nd Color"]);
+    assert_eq!(rows(&sheet, &with_naming(ColumnNaming::CamelCase)), [
+        json!({ "name": "Carp", "uniqueEntryId": "abc", "buyBells": "300", "_2ndColor": "Red" }),
+    ]);
+}
+
+#[test]
+fn columns_are_renamed_after_naming() {
+    let sheet = naming_sheet();
+    let options = JsonOptions {
+        column_renames: vec![("buyBells".to_owned(), "price".to_owned())].into_iter().collect(),
+        ..with_naming(ColumnNaming::CamelCase)
+    };
+
+    assert_eq!(sheet.column_keys(&options).unwrap(), ["name", "uniqueEntryId", "price", "_2ndColor"]);
+}
+
+#[test]
+fn column_keys_by_legacy_name() {
+    let sheet = naming_sheet();
+    let renamed = JsonOptions {
+        column_renames: vec![("uniqueEntryId".to_owned(), "id".to_owned())].into_iter().collect(),
+        ..with_naming(ColumnNaming::CamelCase)
+    };
+
+    for (options, key) in &[
+        (with_naming(ColumnNaming::Legacy), "unique_entry_id"),
+        (with_naming(ColumnNaming::SnakeCase), "unique_entry_id"),
+        (with_naming(ColumnNaming::CamelCase), "uniqueEntryId"),
+        (with_naming(ColumnNaming::Verbatim), "Unique Entry ID"),
+        (renamed, "id"),
+    ] {
+        let keys = sheet.column_keys_by_legacy_name(options).unwrap();
+
+        assert_eq!(keys["unique_entry_id"], *key);
+        assert_eq!(keys.len(), 4);
+    }
+}
+
+/// Returns a grid data block starting at the given row and column.
+fn block(start_row: usize, start_column: usize, rows: &[Vec<Value>]) -> Value {
+    let rows = rows.iter()
+        .map(|values| json!({ "values": values }))
+        .collect::<Vec<_>>();
+
+    json!({ "startRow": start_row, "startColumn": start_column, "rowData": rows })
+}
+
+#[test]
+fn stitches_grid_data_blocks_by_offset() {
+    let sheet = sheet_with(&[], json!({
+        "data": [
+            block(0, 0, &[vec![text("Name"), text("Buy")]]),
+            block(1, 0, &[vec![text("Carp")], vec![text("Koi")]]),
+            block(1, 1, &[vec![text("300")], vec![text("4000")]]),
+        ],
+    }));
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [
+        json!({ "name": "Carp", "buy": "300" }),
+        json!({ "name": "Koi", "buy": "4000" }),
+    ]);
+}
+
+#[test]
+fn later_grid_data_blocks_take_precedence() {
+    let sheet = sheet_with(&[], json!({
+        "data": [
+            block(0, 0, &[vec![text("Name")], vec![text("Carp")]]),
+            block(1, 0, &[vec![text("Koi")]]),
+        ],
+    }));
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "name": "Koi" })]);
+}
+
+#[test]
+fn grid_data_blocks_may_leave_gaps() {
+    let sheet = sheet_with(&[], json!({
+        "data": [
+            block(0, 0, &[vec![text("Name"), text("Buy"), text("Sell")]]),
+            block(3, 2, &[vec![text("75")]]),
+        ],
+    }));
+
+    let grid = sheet.grid().unwrap();
+    assert_eq!(grid.rows().len(), 4);
+    assert!(grid.cell(1, 0).is_none());
+    assert!(grid.cell(3, 0).is_none());
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "sell": "75" })]);
+}
+
+#[test]
+#[allow(deprecated)]
+fn rows_returns_first_grid_data_block_without_header() {
+    let sheet = sheet_with(&[], json!({
+        "data": [
+            block(0, 0, &[vec![text("Name")], vec![text("Carp")]]),
+            block(2, 0, &[vec![text("Koi")]]),
+        ],
+    }));
+
+    let rows = sheet.rows().unwrap();
+
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].values.len(), 1);
+}
+
+/// Returns `cell` with the given effective format.
+fn formatted(cell: Value, format: Value) -> Value {
+    with(cell, json!({ "effectiveFormat": format }))
+}
+
+fn format_flags() -> Vec<FormatFlag> {
+    vec![
+        FormatFlag::new(FormatCondition::Background([255, 0, 0]), "unobtainable"),
+        FormatFlag::new(FormatCondition::Bold, "new"),
+        FormatFlag::new(FormatCondition::Strikethrough, "cut"),
+    ]
+}
+
+#[test]
+fn collects_format_flags_by_column() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![
+            formatted(text("Carp"), json!({ "backgroundColor": { "red": 1 }, "textFormat": { "bold": true } })),
+            formatted(text("300"), json!({ "textFormat": { "strikethrough": true } })),
+        ],
+        vec![
+            formatted(text("Koi"), json!({ "backgroundColor": { "red": 1, "green": 1, "blue": 1 } })),
+            formatted(text("4000"), json!({ "textFormat": { "bold": false } })),
+        ],
+    ]);
+    let options = JsonOptions {
+        format_flags: format_flags(),
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "buy": "300", "_flags": { "name": ["unobtainable", "new"], "buy": ["cut"] } }),
+        // Rows without flags get no `_flags` map
+        json!({ "name": "Koi", "buy": "4000" }),
+    ]);
+}
+
+#[test]
+fn ignores_formats_without_format_flags() {
+    let sheet = sheet(&[
+        vec![text("Name")],
+        vec![formatted(text("Carp"), json!({ "textFormat": { "bold": true } }))],
+    ]);
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "name": "Carp" })]);
+}
+
+#[test]
+fn requests_effective_format_only_with_format_flags() {
+    let options = JsonOptions {
+        format_flags: format_flags(),
+        ..JsonOptions::default()
+    };
+
+    assert!(!JsonOptions::default().fields_mask().contains("effectiveFormat"));
+    assert!(options.fields_mask().contains("effectiveFormat"));
+}
+
+/// Returns `cell` with a dropdown allowing `values`.
+fn dropdown(cell: Value, values: &[&str]) -> Value {
+    let values = values.iter()
+        .map(|value| json!({ "userEnteredValue": value }))
+        .collect::<Vec<_>>();
+
+    with(cell, json!({ "dataValidation": { "condition": { "type": "ONE_OF_LIST", "values": values } } }))
+}
+
+fn validate_enums(strictness: Strictness) -> JsonOptions {
+    JsonOptions {
+        validate_enums: true,
+        strictness,
+        ..JsonOptions::default()
+    }
+}
+
+#[test]
+fn collects_enums_of_dropdown_columns() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Color")],
+        vec![text("Carp"), dropdown(text("Red"), &["Red", "Blue"])],
+        vec![text("Koi"), dropdown(text("Blue"), &["Blue", "Green"])],
+    ]);
+
+    let json_rows = sheet.json_rows_with(&JsonOptions::default()).unwrap();
+
+    assert_eq!(json_rows.enums.len(), 1);
+    assert_eq!(json_rows.enums["color"], ["Red", "Blue", "Green"]);
+}
+
+#[test]
+fn rejects_values_not_allowed_by_dropdown() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Color")],
+        vec![text("Carp"), dropdown(text("Purple"), &["Red", "Blue"])],
+    ]);
+
+    let error = sheet.json_rows_with(&validate_enums(Strictness::Strict)).unwrap_err();
+    let error = error.downcast_ref::<CellError>().unwrap();
+
+    assert_eq!(error.column, "color");
+    assert_eq!(error.error.kind, DecodeErrorKind::NotAllowed);
+    assert_eq!(error.error.raw_value, "Purple");
+
+    // Kept with a warning in lenient mode
+    let json_rows = sheet.json_rows_with(&validate_enums(Strictness::Lenient)).unwrap();
+
+    assert_eq!(Value::Object(json_rows.rows[0].clone()), json!({ "name": "Carp", "color": "Purple" }));
+    assert_eq!(json_rows.warnings.len(), 1);
+    assert_eq!(json_rows.warnings[0].error.kind, DecodeErrorKind::NotAllowed);
+}
+
+#[test]
+fn validates_only_cells_with_dropdown() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Color")],
+        vec![text("Carp"), dropdown(text("Red"), &["Red", "Blue"])],
+        // Same column, but without a dropdown
+        vec![text("Koi"), text("Purple")],
+        // Other rules are ignored
+        vec![text("Pike"), with(text("Green"), json!({
+            "dataValidation": { "condition": { "type": "TEXT_CONTAINS", "values": [{ "userEnteredValue": "Red" }] } },
+        }))],
+    ]);
+
+    let json_rows = sheet.json_rows_with(&validate_enums(Strictness::Lenient)).unwrap();
+
+    assert_eq!(json_rows.rows.len(), 3);
+    assert!(json_rows.warnings.is_empty());
+}
+
+#[test]
+fn compares_entered_numbers_and_booleans_by_value() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Size"), text("DIY")],
+        vec![text("Carp"), dropdown(number(1.), &["1.0", "2"]), dropdown(boolean(true), &["TRUE", "FALSE"])],
+        vec![text("Koi"), dropdown(number(3.), &["1.0", "2"]), dropdown(boolean(false), &["TRUE"])],
+    ]);
+
+    let json_rows = sheet.json_rows_with(&validate_enums(Strictness::Lenient)).unwrap();
+    let invalid = json_rows.warnings.iter()
+        .map(|warning| (warning.row, warning.column.as_str()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(invalid, [(2, "size"), (2, "diy")]);
+}
+
+#[test]
+fn does_not_validate_formulas() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Color")],
+        vec![text("Carp"), dropdown(with(formula("=A1"), json!({ "effectiveValue": { "stringValue": "Carp" } })), &["Red"])],
+    ]);
+
+    let json_rows = sheet.json_rows_with(&validate_enums(Strictness::Strict)).unwrap();
+
+    assert!(json_rows.warnings.is_empty());
+}
+
+fn with_source(spreadsheet_id: Option<&str>) -> JsonOptions {
+    JsonOptions {
+        source: true,
+        spreadsheet_id: spreadsheet_id.map(str::to_owned),
+        ..JsonOptions::default()
+    }
+}
+
+#[test]
+fn source_locates_rows_in_spreadsheet() {
+    let sheet = sheet(&[
+        vec![text("Name")],
+        vec![],
+        vec![text("Carp")],
+    ]);
+
+    assert_eq!(rows(&sheet, &with_source(Some("abc"))), [json!({
+        "name": "Carp",
+        "_source": {
+            "spreadsheet_id": "abc",
+            "gid": 7,
+            // Skipped rows are counted
+            "row": 3,
+            "a1": "'Test'!A3",
+            "url": "https://docs.google.com/spreadsheets/d/abc/edit#gid=7&range=A3",
+        },
+    })]);
+}
+
+#[test]
+fn source_without_spreadsheet_id_has_no_url() {
+    let sheet = sheet(&[vec![text("Name")], vec![text("Carp")]]);
+
+    let rows = rows(&sheet, &with_source(None));
+
+    assert_eq!(rows[0]["_source"]["spreadsheet_id"], Value::Null);
+    assert_eq!(rows[0]["_source"]["url"], Value::Null);
+    assert_eq!(rows[0]["_source"]["row"], 2);
+}
+
+#[test]
+fn source_counts_rows_of_moved_header() {
+    let sheet = sheet(&[
+        vec![text("Fish")],
+        vec![text("Name")],
+        vec![text("Carp")],
+    ]);
+    let options = JsonOptions {
+        header: Header { row: 1, ..Header::default() },
+        ..with_source(None)
+    };
+
+    let rows = rows(&sheet, &options);
+
+    assert_eq!(rows[0]["_source"]["row"], 3);
+    assert_eq!(rows[0]["_source"]["a1"], "'Test'!A3");
+}
+
+#[test]
+fn source_quotes_sheet_titles() {
+    let sheet = sheet_with(&[vec![text("Name")], vec![text("Carp")]], json!({
+        "properties": { "title": "Fish 'n' Bugs", "sheetId": 7, "index": 2 },
+    }));
+
+    let rows = rows(&sheet, &with_source(None));
+
+    assert_eq!(rows[0]["_source"]["a1"], "'Fish ''n'' Bugs'!A2");
+}
+
+#[test]
+fn no_source_by_default() {
+    let sheet = sheet(&[vec![text("Name")], vec![text("Carp")]]);
+
+    assert_eq!(rows(&sheet, &JsonOptions::default()), [json!({ "name": "Carp" })]);
+}
+
+#[test]
+fn sentinels_replace_placeholders() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy"), text("DIY"), text("Color")],
+        vec![text("Carp"), text("NA"), text("Yes"), text("-")],
+        vec![text("Koi"), text("4000"), text("No"), text("None")],
+    ]);
+    let options = JsonOptions {
+        sentinels: Sentinels::datamine(),
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "Carp", "buy": null, "diy": true, "color": null }),
+        json!({ "name": "Koi", "buy": "4000", "diy": false, "color": null }),
+    ]);
+}
+
+#[test]
+fn rows_with_only_placeholders_are_skipped() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("NA"), text("-")],
+        vec![text("Carp"), text("NA")],
+    ]);
+    let options = JsonOptions {
+        sentinels: Sentinels::datamine(),
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [json!({ "name": "Carp", "buy": null })]);
+}
+
+#[test]
+fn column_sentinels_take_precedence_over_global_ones() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy (Bells)")],
+        vec![text("NFS"), text("NFS")],
+        vec![text("NA"), text("NA")],
+    ]);
+    let mut sentinels = Sentinels::datamine();
+    sentinels.extend(json::from_value(json!({
+        "columns": { "buy_price": { "NFS": null, "NA": 0 } },
+    })).unwrap());
+    let options = JsonOptions {
+        sentinels,
+        // Column rules use the final column keys
+        column_renames: vec![("buy_(bells)".to_owned(), "buy_price".to_owned())].into_iter().collect(),
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [
+        json!({ "name": "NFS", "buy_price": null }),
+        json!({ "name": null, "buy_price": 0 }),
+    ]);
+}
+
+#[test]
+fn sentinels_only_replace_text() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), number(0.)],
+    ]);
+    let options = JsonOptions {
+        typed_values: true,
+        sentinels: json::from_value(json!({ "global": { "0": null } })).unwrap(),
+        ..JsonOptions::default()
+    };
+
+    assert_eq!(rows(&sheet, &options), [json!({ "name": "Carp", "buy": 0 })]);
+}
+
+#[test]
+fn sentinels_do_not_replace_error_values() {
+    let sheet = sheet(&[
+        vec![text("Name"), text("Buy")],
+        vec![text("Carp"), error_value("N_A", "=VLOOKUP(A2, B:B, 1)")],
+    ]);
+    let options = JsonOptions {
+        error_values: ErrorValueMode::Tagged,
+        sentinels: json::from_value(json!({ "global": { "#N/A": 0 } })).unwrap(),
+        ..JsonOptions::default()
+    };
+
+    let rows = rows(&sheet, &options);
+
+    assert_eq!(rows[0]["buy"]["error"], "#N/A");
+}
+
+/// Sheet title, index, start column and first value of a streamed row
+type RowSummary = (String, usize, usize, String);
+
+/// Streams the rows of the spreadsheet `json` and returns a summary of each row,
+/// together with the titles of the sheets.
+fn stream_rows(json: &str, selector: &SheetSelector) -> Result<(Vec<RowSummary>, Vec<String>)> {
+    let mut rows = Vec::new();
+    let mut sheets = Vec::new();
+
+    Spreadsheet::for_each_row(
+        json.as_bytes(),
+        selector,
+        |properties, row| {
+            let value = row.data[0].to_string()?.unwrap_or_default().into_owned();
+            rows.push((properties.title().to_owned(), row.index, row.start_column, value));
+            Ok(())
+        },
+        |sheet| {
+            assert!(sheet.data.iter().all(|grid_data| grid_data.row_data.is_empty()));
+            sheets.push(sheet.title().to_owned());
+            Ok(())
+        },
+    )?;
+
+    Ok((rows, sheets))
+}
+
+/// Returns a row with a single text cell as json.
+/// The keys of `json!` objects are sorted, so the spreadsheets streamed in tests are written by hand.
+fn row_data(value: &str) -> String {
+    json!({ "values": [text(value)] }).to_string()
+}
+
+#[test]
+fn streams_rows_at_their_offsets() {
+    let json = format!(
+        r#"{{ "sheets": [
+            {{ "properties": {{ "title": "Fish" }}, "data": [
+                {{ "rowData": [{}, {}] }},
+                {{ "startRow": 5, "startColumn": 2, "rowData": [{}] }}
+            ] }},
+            {{ "properties": {{ "title": "Bugs" }}, "data": [{{ "rowData": [{}] }}] }}
+        ] }}"#,
+        row_data("Name"),
+        row_data("Carp"),
+        row_data("Koi"),
+        row_data("Name"),
+    );
+
+    let (rows, sheets) = stream_rows(&json, &SheetSelector::new()).unwrap();
+
+    assert_eq!(rows, [
+        ("Fish".to_owned(), 0, 0, "Name".to_owned()),
+        ("Fish".to_owned(), 1, 0, "Carp".to_owned()),
+        ("Fish".to_owned(), 5, 2, "Koi".to_owned()),
+        ("Bugs".to_owned(), 0, 0, "Name".to_owned()),
+    ]);
+    assert_eq!(sheets, ["Fish", "Bugs"]);
+
+    let (rows, sheets) = stream_rows(&json, &SheetSelector::new().title("Bugs")).unwrap();
+
+    assert_eq!(rows, [("Bugs".to_owned(), 0, 0, "Name".to_owned())]);
+    assert_eq!(sheets, ["Bugs"]);
+}
+
+#[test]
+fn streaming_rows_fails_for_offsets_after_rows() {
+    let json = |start_row: usize| format!(
+        r#"{{ "sheets": [{{ "properties": {{ "title": "Fish" }}, "data": [{{ "rowData": [{}], "startRow": {} }}] }}] }}"#,
+        row_data("Carp"),
+        start_row,
+    );
+
+    assert!(stream_rows(&json(0), &SheetSelector::new()).is_ok());
+    assert!(stream_rows(&json(5), &SheetSelector::new()).is_err());
+}
+
+#[test]
+fn streaming_rows_fails_for_data_before_properties() {
+    let json = r#"{ "sheets": [{ "data": [{ "rowData": [] }], "properties": { "title": "Fish" } }] }"#;
+
+    assert!(stream_rows(json, &SheetSelector::new()).is_err());
+}
@@ -0,0 +1,203 @@
+//! Tests service account authentication against a local stand-in for the token endpoint.
+
+mod mock_server;
+
+use std::{cell::Cell, convert::TryFrom, path::Path};
+use datamine_exporter::spreadsheet::{
+    client::{FnInstrument, Instrument},
+    CachePolicy, Client, ServiceAccount,
+};
+use mock_server::{cache_dir, MockResponse, MockServer};
+use rsa::{
+    pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::{EncodePrivateKey, LineEnding},
+    signature::Verifier,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+const SPREADSHEET_ID: &str = "fixture";
+const SHEETS_PATH: &str = "/v4/spreadsheets/fixture";
+const DRIVE_PATH: &str = "/drive/v3/files/fixture";
+const TOKEN_PATH: &str = "/token";
+const FIXTURE: &[u8] = include_bytes!("fixtures/spreadsheet.json");
+
+/// Returns a service account with a new private key and its public key.
+fn service_account(server: &MockServer) -> (ServiceAccount, RsaPublicKey) {
+    let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let key = json!({
+        "type": "service_account",
+        "client_email": "exporter@example.iam.gserviceaccount.com",
+        "private_key": private_key.to_pkcs8_pem(LineEnding::LF).unwrap().as_str(),
+        "private_key_id": "key-1",
+        "token_uri": server.url(TOKEN_PATH).as_str(),
+    });
+
+    let service_account = ServiceAccount::new(serde_json::from_value(key).unwrap()).unwrap();
+
+    (service_account, private_key.to_public_key())
+}
+
+fn client(server: &MockServer, service_account: ServiceAccount, cache_dir: &Path) -> Client {
+    Client::builder(service_account)
+        .cache_dir(cache_dir)
+        .cache_policy(CachePolicy::Refresh)
+        .sheets_url(server.url("/v4/spreadsheets/"))
+        .drive_url(server.url("/drive/v3/files/"))
+        .build()
+}
+
+fn token(token: &str, expires_in: u64) -> MockResponse {
+    MockResponse::json(json!({ "access_token": token, "expires_in": expires_in, "token_type": "Bearer" }).to_string())
+}
+
+fn instrument() -> impl Instrument {
+    FnInstrument {
+        this: Cell::new(0),
+        starting_request: |_| {},
+        received_bytes: |_, _| {},
+        request_finished: |_| {},
+        retrying: |_, _, _, _| {},
+        using_stale_cache: |_, _| {},
+    }
+}
+
+fn decode_base64_url(data: &str) -> Vec<u8> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).unwrap()
+}
+
+#[tokio::test]
+async fn authorizes_with_cached_access_token() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, MockResponse::json(r#"{ "version": "1" }"#));
+    server.respond(TOKEN_PATH, token("token-1", 3600));
+
+    let (service_account, public_key) = service_account(&server);
+    let cache_dir = cache_dir("authorizes_with_cached_access_token");
+    let client = client(&server, service_account, &cache_dir);
+
+    for _ in 0..2 {
+        let raw = client.get_raw(SPREADSHEET_ID, &instrument()).await.unwrap();
+        assert_eq!(raw, FIXTURE);
+    }
+
+    let sheets_requests = server.received(SHEETS_PATH);
+    assert_eq!(sheets_requests.len(), 2);
+
+    for request in sheets_requests.iter().chain(&server.received(DRIVE_PATH)) {
+        assert_eq!(request.headers["authorization"], "Bearer token-1");
+        assert!(!request.target.contains("key="));
+    }
+
+    let token_requests = server.received(TOKEN_PATH);
+    assert_eq!(token_requests.len(), 1);
+    assert_eq!(token_requests[0].method, "POST");
+
+    let body = &token_requests[0].body;
+    assert!(body.contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer"));
+
+    let assertion = body.split('&')
+        .find_map(|pair| pair.strip_prefix("assertion="))
+        .unwrap();
+    let parts = assertion.split('.').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 3);
+
+    let header: Value = serde_json::from_slice(&decode_base64_url(parts[0])).unwrap();
+    assert_eq!(header, json!({ "alg": "RS256", "typ": "JWT", "kid": "key-1" }));
+
+    let claims: Value = serde_json::from_slice(&decode_base64_url(parts[1])).unwrap();
+    assert_eq!(claims["iss"], "exporter@example.iam.gserviceaccount.com");
+    assert_eq!(claims["aud"], server.url(TOKEN_PATH).as_str());
+    assert!(claims["scope"].as_str().unwrap().contains("spreadsheets.readonly"));
+    assert_eq!(claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap(), 3600);
+
+    let verifying_key = VerifyingKey::<Sha256>::new(public_key);
+    let signature = Signature::try_from(decode_base64_url(parts[2]).as_slice()).unwrap();
+    verifying_key.verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature).unwrap();
+}
+
+#[tokio::test]
+async fn refreshes_expiring_access_token() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, MockResponse::json(r#"{ "version": "1" }"#));
+    // Expires within the refresh margin, so it is only used once
+    server.respond(TOKEN_PATH, token("token-1", 30));
+    server.respond(TOKEN_PATH, token("token-2", 3600));
+
+    let (service_account, _) = service_account(&server);
+    let cache_dir = cache_dir("refreshes_expiring_access_token");
+    let client = client(&server, service_account, &cache_dir);
+
+    // The second download checks the revision first
+    for _ in 0..2 {
+        client.get_raw(SPREADSHEET_ID, &instrument()).await.unwrap();
+    }
+
+    let authorizations = server.received(SHEETS_PATH).into_iter()
+        .chain(server.received(DRIVE_PATH))
+        .map(|request| request.headers["authorization"].clone())
+        .collect::<Vec<_>>();
+
+    assert_eq!(authorizations, ["Bearer token-1", "Bearer token-2", "Bearer token-2"]);
+    assert_eq!(server.received(TOKEN_PATH).len(), 2);
+}
+
+#[tokio::test]
+async fn fails_when_token_is_denied() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(TOKEN_PATH, MockResponse::status(400));
+
+    let (service_account, _) = service_account(&server);
+    let cache_dir = cache_dir("fails_when_token_is_denied");
+    let client = client(&server, service_account, &cache_dir);
+
+    let error = client.get_raw(SPREADSHEET_ID, &instrument()).await.unwrap_err();
+
+    assert!(format!("{:#}", error).contains("Failed to get access token"));
+    assert!(server.received(SHEETS_PATH).is_empty());
+}
+
+#[tokio::test]
+async fn gets_new_access_token_when_rejected() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::status(401));
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(TOKEN_PATH, token("token-1", 3600));
+    server.respond(TOKEN_PATH, token("token-2", 3600));
+
+    let (service_account, _) = service_account(&server);
+    let cache_dir = cache_dir("gets_new_access_token_when_rejected");
+    let client = client(&server, service_account, &cache_dir);
+
+    let raw = client.get_raw(SPREADSHEET_ID, &instrument()).await.unwrap();
+    assert_eq!(raw, FIXTURE);
+
+    let authorizations = server.received(SHEETS_PATH).into_iter()
+        .map(|request| request.headers["authorization"].clone())
+        .collect::<Vec<_>>();
+
+    assert_eq!(authorizations, ["Bearer token-1", "Bearer token-2"]);
+    assert_eq!(server.received(TOKEN_PATH).len(), 2);
+}
+
+#[tokio::test]
+async fn gets_new_access_token_only_once() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::status(401));
+    server.respond(TOKEN_PATH, token("token-1", 3600));
+    server.respond(TOKEN_PATH, token("token-2", 3600));
+
+    let (service_account, _) = service_account(&server);
+    let cache_dir = cache_dir("gets_new_access_token_only_once");
+    let client = client(&server, service_account, &cache_dir);
+
+    let error = client.get_raw(SPREADSHEET_ID, &instrument()).await.unwrap_err();
+
+    assert!(format!("{:#}", error).contains("401"));
+    assert_eq!(server.received(SHEETS_PATH).len(), 2);
+    assert_eq!(server.received(TOKEN_PATH).len(), 2);
+}
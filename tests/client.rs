@@ -0,0 +1,542 @@
+//! Tests the download, caching and error handling of `Client` against a local mock server.
+
+mod mock_server;
+
+use std::{cell::{Cell, RefCell}, fs, path::Path, time::Duration};
+use datamine_exporter::spreadsheet::{
+    client::{FnInstrument, Instrument, RetryPolicy},
+    sheet_range, CachePolicy, Client, ClientBuilder, JsonOptions, SheetSelector,
+};
+use mock_server::{cache_dir, MockResponse, MockServer};
+
+const SPREADSHEET_ID: &str = "fixture";
+const SHEETS_PATH: &str = "/v4/spreadsheets/fixture";
+const DRIVE_PATH: &str = "/drive/v3/files/fixture";
+const FIXTURE: &[u8] = include_bytes!("fixtures/spreadsheet.json");
+
+fn client(server: &MockServer, cache_dir: &Path) -> Client {
+    builder(server, cache_dir).build()
+}
+
+fn builder(server: &MockServer, cache_dir: &Path) -> ClientBuilder {
+    Client::builder("test_key")
+        .cache_dir(cache_dir)
+        .sheets_url(server.url("/v4/spreadsheets"))
+        .drive_url(server.url("/drive/v3/files/"))
+        .retry_policy(RetryPolicy {
+            max_retries: 2,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        })
+}
+
+fn revision(version: &str) -> MockResponse {
+    revision_modified_at(version, "2020-06-01T00:00:00Z")
+}
+
+fn revision_modified_at(version: &str, modified_time: &str) -> MockResponse {
+    MockResponse::json(format!(r#"{{ "modifiedTime": "{}", "version": "{}" }}"#, modified_time, version))
+}
+
+/// Counts retries reported by the client.
+fn retry_counter(retries: &Cell<u32>) -> impl Instrument + '_ {
+    FnInstrument {
+        this: retries,
+        starting_request: |_| {},
+        received_bytes: |_, _| {},
+        request_finished: |_| {},
+        retrying: |retries, _, _, _| retries.set(retries.get() + 1),
+        using_stale_cache: |_, _| {},
+    }
+}
+
+#[tokio::test]
+async fn downloads_and_caches_spreadsheet() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision("1"));
+
+    let cache_dir = cache_dir("downloads_and_caches_spreadsheet");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let spreadsheet = client.get(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    let titles = spreadsheet.sheets().map(|sheet| sheet.title()).collect::<Vec<_>>();
+    assert_eq!(titles, ["Read Me", "Fish"]);
+    // Without a cache, there is no need to check for changes
+    assert!(server.requests(DRIVE_PATH).is_empty());
+
+    let raw = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    assert_eq!(raw, FIXTURE);
+
+    let requests = server.requests(SHEETS_PATH);
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].contains("includeGridData=true"));
+    assert!(requests[0].contains("key=test_key"));
+    assert!(cache_dir.join("fixture.json.gz").is_file());
+    assert!(cache_dir.join("fixture.meta.json").is_file());
+    assert_eq!(server.requests(DRIVE_PATH).len(), 1);
+    assert_eq!(retries.get(), 0);
+}
+
+#[tokio::test]
+async fn downloads_again_when_revision_changes() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision("1"));
+    server.respond(DRIVE_PATH, revision("2"));
+
+    let cache_dir = cache_dir("downloads_again_when_revision_changes");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    for _ in 0..3 {
+        client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    }
+
+    assert_eq!(server.requests(DRIVE_PATH).len(), 2);
+    assert_eq!(server.requests(SHEETS_PATH).len(), 2);
+}
+
+#[tokio::test]
+async fn downloads_again_when_modified_after_first_download() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision_modified_at("1", "2999-01-01T00:00:00.000Z"));
+
+    let cache_dir = cache_dir("downloads_again_when_modified_after_first_download");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    for _ in 0..3 {
+        client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    }
+
+    // The revision of the second download is known, so the third run compares it
+    assert_eq!(server.requests(DRIVE_PATH).len(), 2);
+    assert_eq!(server.requests(SHEETS_PATH).len(), 2);
+}
+
+#[tokio::test]
+async fn cache_policies_override_revision_check() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision("1"));
+
+    let cache_dir = cache_dir("cache_policies_override_revision_check");
+    let retries = Cell::new(0);
+
+    let client = Client::builder("test_key")
+        .cache_dir(&*cache_dir)
+        .sheets_url(server.url("/v4/spreadsheets/"))
+        .drive_url(server.url("/drive/v3/files/"))
+        .cache_policy(CachePolicy::Refresh);
+
+    for _ in 0..2 {
+        client.clone().build().get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    }
+
+    assert_eq!(server.requests(SHEETS_PATH).len(), 2);
+
+    let drive_requests = server.requests(DRIVE_PATH).len();
+    let client = client.cache_policy(CachePolicy::MaxAge(Duration::from_secs(3600))).build();
+    client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+
+    assert_eq!(server.requests(SHEETS_PATH).len(), 2);
+    assert_eq!(server.requests(DRIVE_PATH).len(), drive_requests);
+}
+
+#[tokio::test]
+async fn fails_when_revision_check_fails() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, MockResponse::status(403));
+
+    let cache_dir = cache_dir("fails_when_revision_check_fails");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    let error = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap_err();
+
+    assert!(format!("{:#}", error).contains("Failed to check whether the cached spreadsheet changed"));
+    assert_eq!(server.requests(SHEETS_PATH).len(), 1);
+}
+
+#[tokio::test]
+async fn stale_if_error_uses_cache_when_revision_check_fails() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, MockResponse::status(403));
+
+    let cache_dir = cache_dir("stale_if_error_uses_cache_when_revision_check_fails");
+    let client = builder(&server, &cache_dir).stale_if_error(true).build();
+    let warnings = RefCell::new(Vec::new());
+    let instrument = FnInstrument {
+        this: &warnings,
+        starting_request: |_| {},
+        received_bytes: |_, _| {},
+        request_finished: |_| {},
+        retrying: |_, _, _, _| {},
+        using_stale_cache: |warnings, reason| warnings.borrow_mut().push(reason.to_owned()),
+    };
+
+    for _ in 0..2 {
+        client.get_raw(SPREADSHEET_ID, &instrument).await.unwrap();
+    }
+
+    assert_eq!(server.requests(SHEETS_PATH).len(), 1);
+    assert_eq!(server.requests(DRIVE_PATH).len(), 1);
+    // Only the second download used the cache
+    assert_eq!(warnings.borrow().len(), 1);
+    assert!(warnings.borrow()[0].contains("403"), "{}", warnings.borrow()[0]);
+}
+
+#[tokio::test]
+async fn retries_revision_check() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, MockResponse::status(503));
+    server.respond(DRIVE_PATH, revision("1"));
+
+    let cache_dir = cache_dir("retries_revision_check");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    for _ in 0..2 {
+        client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    }
+
+    assert_eq!(server.requests(DRIVE_PATH).len(), 2);
+    assert_eq!(server.requests(SHEETS_PATH).len(), 1);
+}
+
+#[tokio::test]
+async fn retries_temporary_errors() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::status(503));
+    server.respond(SHEETS_PATH, MockResponse::status(429).header("Retry-After", "0"));
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+
+    let cache_dir = cache_dir("retries_temporary_errors");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let raw = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+
+    assert_eq!(raw, FIXTURE);
+    assert_eq!(server.requests(SHEETS_PATH).len(), 3);
+    assert_eq!(retries.get(), 2);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_retries() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::status(500));
+
+    let cache_dir = cache_dir("gives_up_after_max_retries");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let result = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await;
+
+    assert!(result.is_err());
+    assert_eq!(server.requests(SHEETS_PATH).len(), 3);
+    assert_eq!(retries.get(), 2);
+    assert!(!cache_dir.join("fixture.json.gz").exists());
+}
+
+#[tokio::test]
+async fn removes_partial_download() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE).truncated(100));
+
+    let cache_dir = cache_dir("removes_partial_download");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let result = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await;
+
+    assert!(result.is_err());
+    assert_eq!(server.requests(SHEETS_PATH).len(), 3);
+    assert!(!cache_dir.join("fixture.json.gz").exists());
+    assert!(!cache_dir.join("fixture.json.tmp").exists());
+}
+
+#[tokio::test]
+async fn does_not_retry_client_errors() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::status(403));
+
+    let cache_dir = cache_dir("does_not_retry_client_errors");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let error = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap_err();
+
+    assert!(format!("{:#}", error).contains("403"));
+    assert_eq!(server.requests(SHEETS_PATH).len(), 1);
+    assert_eq!(retries.get(), 0);
+    assert!(!cache_dir.join("fixture.json.gz").exists());
+}
+
+#[tokio::test]
+async fn streams_selected_sheets() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision("1"));
+
+    let cache_dir = cache_dir("streams_selected_sheets");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+    let selector = SheetSelector::new().exclude("Read Me");
+    let mut rows = Vec::new();
+
+    client.for_each_sheet(SPREADSHEET_ID, &retry_counter(&retries), &selector, |sheet| {
+        rows.extend(sheet.json_rows()?);
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    let names = rows.iter().map(|row| row["name"].as_str().unwrap()).collect::<Vec<_>>();
+    assert_eq!(names, ["bitterling", "sea bass"]);
+}
+
+#[tokio::test]
+async fn migrates_uncompressed_cache() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    // Modified before the uncompressed cache was written
+    server.respond(DRIVE_PATH, revision_modified_at("1", "2020-06-01T00:00:00Z"));
+
+    let cache_dir = cache_dir("migrates_uncompressed_cache");
+    fs::create_dir_all(&cache_dir).unwrap();
+    fs::write(cache_dir.join(SPREADSHEET_ID), FIXTURE).unwrap();
+
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let raw = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+
+    assert_eq!(raw, FIXTURE);
+    assert!(server.requests(SHEETS_PATH).is_empty());
+    assert_eq!(server.requests(DRIVE_PATH).len(), 1);
+    assert!(!cache_dir.join(SPREADSHEET_ID).exists());
+    assert!(cache_dir.join("fixture.json.gz").is_file());
+}
+
+#[tokio::test]
+async fn migrates_uncompressed_cache_for_field_masks() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision_modified_at("1", "2020-06-01T00:00:00Z"));
+
+    let cache_dir = cache_dir("migrates_uncompressed_cache_for_field_masks");
+    fs::create_dir_all(&cache_dir).unwrap();
+    fs::write(cache_dir.join(SPREADSHEET_ID), FIXTURE).unwrap();
+
+    // Like the exporter, which always requests a fields mask
+    let client = builder(&server, &cache_dir).fields(JsonOptions::default().fields_mask()).build();
+    let retries = Cell::new(0);
+
+    let raw = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+
+    assert_eq!(raw, FIXTURE);
+    assert!(server.requests(SHEETS_PATH).is_empty());
+    assert!(!cache_dir.join(SPREADSHEET_ID).exists());
+}
+
+#[tokio::test]
+async fn removes_uncompressed_cache_for_ranges() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+
+    let cache_dir = cache_dir("removes_uncompressed_cache_for_ranges");
+    fs::create_dir_all(&cache_dir).unwrap();
+    fs::write(cache_dir.join(SPREADSHEET_ID), b"{}").unwrap();
+
+    let client = builder(&server, &cache_dir).ranges(vec![sheet_range("Fish")]).build();
+    let retries = Cell::new(0);
+
+    let raw = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+
+    assert_eq!(raw, FIXTURE);
+    assert_eq!(server.requests(SHEETS_PATH).len(), 1);
+    assert!(!cache_dir.join(SPREADSHEET_ID).exists());
+}
+
+#[tokio::test]
+async fn downloads_again_when_modified_after_uncompressed_cache() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision_modified_at("1", "2999-01-01T00:00:00Z"));
+
+    let cache_dir = cache_dir("downloads_again_when_modified_after_uncompressed_cache");
+    fs::create_dir_all(&cache_dir).unwrap();
+    fs::write(cache_dir.join(SPREADSHEET_ID), b"{}").unwrap();
+
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let raw = client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+
+    assert_eq!(raw, FIXTURE);
+    assert_eq!(server.requests(SHEETS_PATH).len(), 1);
+}
+
+#[tokio::test]
+async fn caches_field_masks_and_ranges_separately() {
+    let server = MockServer::start().await;
+    server.respond(SHEETS_PATH, MockResponse::json(FIXTURE));
+    server.respond(DRIVE_PATH, revision("1"));
+
+    let cache_dir = cache_dir("caches_field_masks_and_ranges_separately");
+    let retries = Cell::new(0);
+    let options = JsonOptions::default();
+    let builder = Client::builder("test_key")
+        .cache_dir(&*cache_dir)
+        .sheets_url(server.url("/v4/spreadsheets/"))
+        .drive_url(server.url("/drive/v3/files/"));
+
+    let clients = [
+        builder.clone().fields(options.fields_mask()).build(),
+        builder.clone().fields(options.fields_mask()).ranges(vec![sheet_range("Fish")]).build(),
+        builder.fields(options.fields_mask()).ranges(vec![sheet_range("Fish")]).build(),
+    ];
+
+    for client in &clients {
+        client.get_raw(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    }
+
+    let requests = server.requests(SHEETS_PATH);
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("fields=sheets%28properties"));
+    assert!(!requests[0].contains("ranges="));
+    assert!(requests[1].contains("ranges=%27Fish%27"));
+
+    let cache_files = fs::read_dir(&cache_dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".json.gz"))
+        .collect::<Vec<_>>();
+    assert_eq!(cache_files.len(), 2);
+    assert!(cache_files.iter().all(|name| name.starts_with("fixture-")));
+}
+
+const SHEET_LIST: &str = r#"{ "sheets": [
+    { "properties": { "sheetId": 0, "title": "Read Me", "index": 0 } },
+    { "properties": { "sheetId": 42, "title": "Fish", "index": 1 } }
+] }"#;
+const READ_ME_SHEET: &str = r#"{ "sheets": [{
+    "properties": { "sheetId": 0, "title": "Read Me", "index": 0 },
+    "data": [{ "rowData": [{ "values": [{ "userEnteredValue": { "stringValue": "Notes" } }] }] }]
+}] }"#;
+const FISH_SHEET: &str = r#"{ "sheets": [{
+    "properties": { "sheetId": 42, "title": "Fish", "index": 1 },
+    "data": [{ "rowData": [
+        { "values": [{ "userEnteredValue": { "stringValue": "Name" } }] },
+        { "values": [{ "userEnteredValue": { "stringValue": "bitterling" } }] }
+    ] }]
+}] }"#;
+
+/// Serves the sheet list and each sheet of the fixture on its own.
+fn respond_per_sheet(server: &MockServer) {
+    server.respond(DRIVE_PATH, revision("1"));
+    server.respond_if(SHEETS_PATH, "fields=sheets%28properties", MockResponse::json(SHEET_LIST));
+    server.respond_if(SHEETS_PATH, "ranges=%27Read+Me%27", MockResponse::json(READ_ME_SHEET));
+    server.respond_if(SHEETS_PATH, "ranges=%27Fish%27", MockResponse::json(FISH_SHEET));
+}
+
+#[tokio::test]
+async fn fetches_sheets_concurrently() {
+    let server = MockServer::start().await;
+    respond_per_sheet(&server);
+
+    let cache_dir = cache_dir("fetches_sheets_concurrently");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+
+    let sheets = client.get_sheet_properties(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    let titles = sheets.iter().map(|sheet| sheet.title()).collect::<Vec<_>>();
+    assert_eq!(titles, ["Read Me", "Fish"]);
+
+    let instruments = Cell::new(0);
+    let mut titles = Vec::new();
+
+    client.for_each_sheet_concurrently(
+        SPREADSHEET_ID,
+        &sheets,
+        2,
+        |_| {
+            instruments.set(instruments.get() + 1);
+            retry_counter(&retries)
+        },
+        |sheet| {
+            titles.push(sheet.title().to_owned());
+            Ok(())
+        },
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(titles, ["Read Me", "Fish"]);
+    assert_eq!(instruments.get(), 2);
+    // The sheet list and both sheets
+    assert_eq!(server.requests(SHEETS_PATH).len(), 3);
+    // Nothing was cached yet
+    assert!(server.requests(DRIVE_PATH).is_empty());
+
+    client.for_each_sheet_concurrently(SPREADSHEET_ID, &sheets, 2, |_| retry_counter(&retries), |_| Ok(()))
+        .await
+        .unwrap();
+
+    assert_eq!(server.requests(SHEETS_PATH).len(), 3);
+    // The revision is only checked once for both sheets
+    assert_eq!(server.requests(DRIVE_PATH).len(), 1);
+
+    let cache_files = fs::read_dir(&cache_dir).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".json.gz"))
+        .count();
+    assert_eq!(cache_files, 2);
+}
+
+#[tokio::test]
+async fn retries_only_failed_sheets() {
+    let server = MockServer::start().await;
+    server.respond(DRIVE_PATH, revision("1"));
+    server.respond_if(SHEETS_PATH, "fields=sheets%28properties", MockResponse::json(SHEET_LIST));
+    server.respond_if(SHEETS_PATH, "ranges=%27Read+Me%27", MockResponse::json(READ_ME_SHEET));
+    server.respond_if(SHEETS_PATH, "ranges=%27Fish%27", MockResponse::status(403));
+    server.respond_if(SHEETS_PATH, "ranges=%27Fish%27", MockResponse::json(FISH_SHEET));
+
+    let cache_dir = cache_dir("retries_only_failed_sheets");
+    let client = client(&server, &cache_dir);
+    let retries = Cell::new(0);
+    let sheets = client.get_sheet_properties(SPREADSHEET_ID, &retry_counter(&retries)).await.unwrap();
+    let mut titles = Vec::new();
+
+    let error = client.for_each_sheet_concurrently(SPREADSHEET_ID, &sheets, 2, |_| retry_counter(&retries), |sheet| {
+        titles.push(sheet.title().to_owned());
+        Ok(())
+    })
+    .await
+    .unwrap_err();
+
+    assert!(error.to_string().contains("Failed to download 1 of 2 sheets: 'Fish'"));
+    assert!(titles.is_empty());
+
+    client.for_each_sheet_concurrently(SPREADSHEET_ID, &sheets, 2, |_| retry_counter(&retries), |sheet| {
+        titles.push(sheet.title().to_owned());
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(titles, ["Read Me", "Fish"]);
+
+    let requests = server.requests(SHEETS_PATH);
+    let read_me_requests = requests.iter().filter(|target| target.contains("ranges=%27Read+Me%27")).count();
+    let fish_requests = requests.iter().filter(|target| target.contains("ranges=%27Fish%27")).count();
+    assert_eq!(read_me_requests, 1);
+    assert_eq!(fish_requests, 2);
+}
@@ -0,0 +1,264 @@
+//! A tiny HTTP server standing in for the Google APIs.
+//!
+//! Every path has a queue of responses. Each request takes the next response from the queue,
+//! the last response is repeated forever. Unknown paths get a `404`.
+//! Responses can also be restricted to requests whose query contains some text.
+//! All requests are recorded, including their headers and body.
+//!
+//! Also provides the cache directories of the tests using the server.
+
+// Each test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    net::SocketAddr,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A cache directory, which is removed when the test finishes.
+pub struct CacheDir(PathBuf);
+
+/// Returns an empty cache directory unique to the test.
+pub fn cache_dir(test: &str) -> CacheDir {
+    let dir = std::env::temp_dir()
+        .join(format!("datamine_exporter_{}_{}", test, std::process::id()));
+
+    let _ = fs::remove_dir_all(&dir);
+
+    CacheDir(dir)
+}
+
+impl Deref for CacheDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for CacheDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for CacheDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// Bytes announced in `Content-Length`, but never sent
+    missing_bytes: usize,
+}
+
+impl MockResponse {
+    pub fn json(body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.into(),
+            missing_bytes: 0,
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            missing_bytes: 0,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Closes the connection `missing_bytes` before the announced end of the body.
+    pub fn truncated(mut self, missing_bytes: usize) -> Self {
+        self.missing_bytes = missing_bytes;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub method: String,
+    /// Path and query
+    pub target: String,
+    /// Header names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl ReceivedRequest {
+    fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("/")
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Responses by path and the text the query has to contain
+    responses: HashMap<(String, String), VecDeque<MockResponse>>,
+    /// Requests in the order they were received
+    requests: Vec<ReceivedRequest>,
+}
+
+impl State {
+    fn next_response(&mut self, path: &str, query: &str) -> MockResponse {
+        // Prefer the most specific match
+        let key = self.responses.keys()
+            .filter(|(route_path, route_query)| route_path == path && query.contains(route_query.as_str()))
+            .max_by_key(|(_, route_query)| route_query.len())
+            .cloned();
+
+        match key.and_then(|key| self.responses.get_mut(&key)) {
+            Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+            Some(queue) => queue.front().cloned().unwrap_or_else(|| MockResponse::status(404)),
+            None => MockResponse::status(404),
+        }
+    }
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await
+            .expect("failed to bind mock server");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        tokio::spawn({
+            let state = state.clone();
+
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(stream, state.clone()));
+                }
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://{}{}", self.addr, path)).unwrap()
+    }
+
+    /// Queues a response for requests to `path`.
+    pub fn respond(&self, path: &str, response: MockResponse) {
+        self.respond_if(path, "", response);
+    }
+
+    /// Queues a response for requests to `path` whose (url encoded) query contains `query`.
+    pub fn respond_if(&self, path: &str, query: &str, response: MockResponse) {
+        self.state.lock().unwrap()
+            .responses
+            .entry((path.into(), query.into()))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Returns the targets of all requests to `path`, including the query.
+    pub fn requests(&self, path: &str) -> Vec<String> {
+        self.received(path).into_iter()
+            .map(|request| request.target)
+            .collect()
+    }
+
+    /// Returns all requests to `path`.
+    pub fn received(&self, path: &str) -> Vec<ReceivedRequest> {
+        self.state.lock().unwrap()
+            .requests
+            .iter()
+            .filter(|request| request.path() == path)
+            .cloned()
+            .collect()
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut data = Vec::new();
+    let mut buf = [0; 1024];
+
+    let head_len = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_len]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("GET").to_owned();
+    let target = request_line.next().unwrap_or("/").to_owned();
+    let headers = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            Some((parts.next()?.trim().to_lowercase(), parts.next()?.trim().to_owned()))
+        })
+        .collect::<HashMap<_, _>>();
+    let content_length = headers.get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+
+    while data.len() < head_len + content_length {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = ReceivedRequest {
+        method,
+        target,
+        headers,
+        body: String::from_utf8_lossy(&data[head_len..]).into_owned(),
+    };
+    let query = request.target.split_once('?').map_or("", |(_, query)| query).to_owned();
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        let response = state.next_response(request.path(), &query);
+        state.requests.push(request);
+        response
+    };
+
+    let mut head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len() + response.missing_bytes);
+
+    for (name, value) in &response.headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+
+    head += "\r\n";
+
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(&response.body).await;
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+}